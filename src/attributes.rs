@@ -0,0 +1,137 @@
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Error;
+use globset::GlobBuilder;
+use globset::GlobMatcher;
+
+/// The attributes which mean a file isn't worth searching.
+const SKIPPED: [&str; 3] = ["binary", "linguist-generated", "linguist-vendored"];
+
+/// Which files `.gitattributes` marks as `binary` (or `-text`), generated or vendored.
+///
+/// Later rules, and rules from deeper directories, win, as in git.
+#[derive(Default)]
+pub struct Attributes {
+    rules: Vec<Rule>,
+}
+
+struct Rule {
+    /// the directory the `.gitattributes` is in, with a trailing `/`, or empty for the root
+    dir: String,
+    glob: GlobMatcher,
+    /// `Some(true)` for set, `Some(false)` for unset, per `SKIPPED`
+    values: [Option<bool>; 3],
+}
+
+impl Attributes {
+    /// Add the rules from the `.gitattributes` at `path`, e.g. `src/.gitattributes`.
+    ///
+    /// Parents must be added before their children.
+    pub fn add(&mut self, path: &str, content: &[u8]) -> Result<(), Error> {
+        let dir = &path[..path.rfind('/').map(|slash| slash + 1).unwrap_or(0)];
+        for (number, line) in String::from_utf8_lossy(content).lines().enumerate() {
+            let mut words = line.split_whitespace();
+            let pattern = match words.next() {
+                Some(pattern) if !pattern.starts_with('#') => pattern,
+                _ => continue,
+            };
+            let mut values = [None; 3];
+            for word in words {
+                let (name, value) = match word.split_once('=') {
+                    Some((name, value)) => (name, Some("false" != value)),
+                    None => match word.strip_prefix(['-', '!']) {
+                        Some(name) => (name, Some(false)),
+                        None => (word, Some(true)),
+                    },
+                };
+                // `-text` is what `binary` expands to
+                let (name, value) = match name {
+                    "text" => ("binary", value.map(|text| !text)),
+                    name => (name, value),
+                };
+                if let Some(pos) = SKIPPED.iter().position(|&known| known == name) {
+                    values[pos] = value;
+                }
+            }
+            if values.iter().all(Option::is_none) {
+                continue;
+            }
+            // patterns naming directories never match in .gitattributes
+            if pattern.ends_with('/') {
+                continue;
+            }
+            self.rules.push(Rule {
+                dir: dir.to_string(),
+                glob: glob(pattern)
+                    .with_context(|| anyhow!("{}:{}: {:?}", path, number + 1, pattern))?,
+                values,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn skip(&self, path: &str) -> bool {
+        let mut values = [None; 3];
+        for rule in &self.rules {
+            let relative = match path.strip_prefix(&rule.dir) {
+                Some(relative) => relative,
+                None => continue,
+            };
+            if !rule.glob.is_match(relative) {
+                continue;
+            }
+            for (value, new) in values.iter_mut().zip(rule.values) {
+                if new.is_some() {
+                    *value = new;
+                }
+            }
+        }
+        values.contains(&Some(true))
+    }
+}
+
+/// A pattern without a `/` matches a file name at any depth, otherwise it's relative to the
+/// directory the `.gitattributes` is in.
+fn glob(pattern: &str) -> Result<GlobMatcher, Error> {
+    let pattern = match pattern.strip_prefix('/') {
+        Some(anchored) => anchored.to_string(),
+        None if !pattern.contains('/') => format!("**/{}", pattern),
+        None => pattern.to_string(),
+    };
+    Ok(GlobBuilder::new(&pattern)
+        .literal_separator(true)
+        .build()?
+        .compile_matcher())
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Error;
+
+    use super::Attributes;
+
+    #[test]
+    fn skip() -> Result<(), Error> {
+        let mut attributes = Attributes::default();
+        attributes.add(
+            ".gitattributes",
+            b"# comment\n*.png binary\n*.lock linguist-generated=true\n/dist/* linguist-vendored\n",
+        )?;
+        attributes.add(
+            "web/.gitattributes",
+            b"keep.lock -linguist-generated\n*.dat -text\nplain.dat text\n",
+        )?;
+
+        assert!(attributes.skip("logo.png"));
+        assert!(attributes.skip("img/logo.png"));
+        assert!(attributes.skip("Cargo.lock"));
+        assert!(attributes.skip("web/yarn.lock"));
+        assert!(!attributes.skip("web/keep.lock"));
+        assert!(attributes.skip("dist/app.js"));
+        assert!(!attributes.skip("web/dist/app.js"));
+        assert!(!attributes.skip("src/main.rs"));
+        assert!(attributes.skip("web/blob.dat"));
+        assert!(!attributes.skip("web/plain.dat"));
+        Ok(())
+    }
+}
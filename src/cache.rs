@@ -6,6 +6,9 @@ use std::path::PathBuf;
 use anyhow::anyhow;
 use anyhow::Error;
 
+use crate::git_url::GitUrl;
+use crate::git_url::Provider;
+
 pub struct Cache {
     root: PathBuf,
 }
@@ -15,6 +18,7 @@ impl Cache {
         Ok(Cache { root: pick()? })
     }
 
+    #[cfg(github)]
     pub fn meta_github_org(&self, org: &str) -> Result<PathBuf, Error> {
         mkdirs(self.root.join("meta/github").join(fs_safe_component(org)))
     }
@@ -27,6 +31,36 @@ impl Cache {
                 .join(format!("{}.git", fs_safe_component(repo))),
         )
     }
+
+    /// Where `fetch` records which repos failed to update in the `workspace`.
+    pub fn failures(&self, workspace: &Path) -> Result<PathBuf, Error> {
+        let name = fs_safe_component(
+            workspace
+                .to_str()
+                .ok_or_else(|| anyhow!("non-utf-8 workspace: {:?}", workspace))?,
+        );
+        Ok(mkdirs(self.root.join("failures"))?.join(format!("{}.json", name)))
+    }
+
+    /// The bare mirror for `url`, shared by every workspace which clones it.
+    pub fn mirror(&self, url: &GitUrl) -> Result<PathBuf, Error> {
+        match url.provider() {
+            Some(Provider::GithubCom { org, repo }) => self.repo_bare(&org, &repo),
+            None => self.repo_bare("_other", url.as_str()),
+        }
+    }
+
+    /// The grep index for `url`, see `index`.
+    pub fn index(&self, url: &GitUrl) -> Result<PathBuf, Error> {
+        let (org, repo) = match url.provider() {
+            Some(Provider::GithubCom { org, repo }) => (org, repo),
+            None => ("_other".to_string(), url.as_str().to_string()),
+        };
+        Ok(
+            mkdirs(self.root.join("index").join(fs_safe_component(&org)))?
+                .join(format!("{}.idx", fs_safe_component(&repo))),
+        )
+    }
 }
 
 fn mkdirs<P: AsRef<Path>>(path: P) -> Result<P, Error> {
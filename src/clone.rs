@@ -0,0 +1,110 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use anyhow::bail;
+use anyhow::Error;
+use log::warn;
+use rayon::iter::IntoParallelIterator;
+use rayon::iter::ParallelIterator;
+
+use super::config;
+use super::git;
+use super::progress;
+use super::Cache;
+use crate::tags::TagFilter;
+use config::Spec;
+
+/// Clone the absent repos, then any more which are included from the repos just cloned.
+pub fn clone(cache: &Cache, filter: &TagFilter, mirror: bool) -> Result<(), Error> {
+    let mut seen = HashSet::new();
+    loop {
+        let specs = config::load_matching(filter)?
+            .into_iter()
+            .filter(|spec| seen.insert(spec.url.normalized()))
+            .collect::<Vec<Spec>>();
+        if specs.is_empty() {
+            return Ok(());
+        }
+        clone_specs(cache, specs, mirror)?;
+    }
+}
+
+/// Clone those of `specs` which aren't checked out yet.
+pub fn clone_specs(cache: &Cache, specs: Vec<Spec>, mirror: bool) -> Result<(), Error> {
+    let absent = specs
+        .into_iter()
+        .filter(|spec| match spec.local_dir() {
+            Ok(dest) => !Path::new(dest).exists(),
+            Err(_) => true,
+        })
+        .collect::<Vec<Spec>>();
+
+    let display = progress::Display::new(absent.len());
+    let results: Vec<(Spec, Result<Option<String>, Error>)> = absent
+        .into_par_iter()
+        .map(|spec| {
+            let result = clone_absent(cache, &spec, mirror, &display);
+            (spec, result)
+        })
+        .collect();
+    display.finish();
+
+    println!(
+        "cloned: {}",
+        results
+            .iter()
+            .filter_map(|(spec, result)| match result {
+                Ok(Some(branch)) => Some(format!("{} ({})", spec.url.as_str(), branch)),
+                Ok(None) => Some(format!("{} (empty)", spec.url.as_str())),
+                Err(_) => None,
+            })
+            .collect::<Vec<String>>()
+            .join(", ")
+    );
+
+    let mut failed = 0;
+    for (spec, result) in &results {
+        if let Err(e) = result {
+            failed += 1;
+            println!("{}: failed: {:#}", spec.url.as_str(), e);
+        }
+    }
+
+    if failed > 0 {
+        bail!("{} of {} clones failed", failed, results.len());
+    }
+
+    Ok(())
+}
+
+fn clone_absent(
+    cache: &Cache,
+    spec: &Spec,
+    mirror: bool,
+    display: &progress::Display,
+) -> Result<Option<String>, Error> {
+    let local_dir = spec.local_dir()?;
+    let dest = Path::new(local_dir);
+    let upstream = git::Upstream {
+        url: spec.url.as_str(),
+        remote: spec.remote(),
+        branch: spec.branch.as_deref(),
+    };
+    let progress = display.start(local_dir);
+    // a mirror has all of the history, which a shallow clone doesn't want
+    let result = if mirror && spec.depth.is_none() {
+        cache.mirror(&spec.url).and_then(|mirror| {
+            git::clone_via_mirror(&upstream, &mirror, dest, |p| progress.update(p))
+        })
+    } else {
+        git::clone(&upstream, spec.depth, dest, |p| progress.update(p))
+    };
+    if result.is_err() && dest.exists() {
+        // don't leave a half-clone around for `status` to trip over
+        if let Err(e) = fs::remove_dir_all(dest) {
+            warn!("cleaning up {:?}: {}", dest, e);
+        }
+    }
+    result
+}
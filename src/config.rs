@@ -1,52 +1,1067 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
 use std::collections::HashSet;
+use std::env;
+use std::fmt;
 use std::fs;
-use std::io;
-use std::io::BufRead;
+use std::io::Read;
+use std::io::Write;
+use std::ops::Range;
+use std::path::Component;
 use std::path::Path;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::OnceLock;
 
 use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::ensure;
 use anyhow::Context;
 use anyhow::Error;
 use anyhow::Result;
+use log::error;
+use log::warn;
 
+use crate::git;
 use crate::git_url::GitUrl;
+use crate::tags;
+use crate::tags::TagFilter;
 
 #[derive(Clone)]
 pub struct Spec {
     pub url: GitUrl,
     pub tags: HashSet<String>,
+    /// where it's checked out, instead of the last part of the url
+    pub path: Option<String>,
+    /// the branch to follow, instead of the remote's default branch
+    pub branch: Option<String>,
+    /// the name of the remote, instead of `origin`
+    pub remote: Option<String>,
+    /// how many commits the first clone fetches, for a shallow clone, which can't use a mirror
+    pub depth: Option<u32>,
+    /// listed, but left alone by every command
+    pub disabled: bool,
+    pub defined: Location,
+    /// where else it's listed, with the same options, which `dedupe` merged into this
+    pub repeats: Vec<Location>,
 }
 
 impl Spec {
-    pub fn html_url(&self) -> Result<&str, Error> {
-        Ok(self.url.as_str())
+    pub fn new(url: GitUrl, tags: HashSet<String>, defined: Location) -> Spec {
+        Spec {
+            url,
+            tags,
+            path: None,
+            branch: None,
+            remote: None,
+            depth: None,
+            disabled: false,
+            defined,
+            repeats: Vec::new(),
+        }
+    }
+
+    pub fn local_dir(&self) -> Result<&str, Error> {
+        match &self.path {
+            Some(path) => Ok(path),
+            None => self.url.local_dir(),
+        }
+    }
+
+    /// Whether everything but the url and tags match.
+    fn same_options(&self, other: &Spec) -> bool {
+        self.path == other.path
+            && self.branch == other.branch
+            && self.remote == other.remote
+            && self.depth == other.depth
+            && self.disabled == other.disabled
+    }
+
+    pub fn remote(&self) -> &str {
+        self.remote.as_deref().unwrap_or("origin")
     }
+
+    /// The remote tracking branch we compare against, e.g. `origin/REMOTE_HEAD`.
+    pub fn upstream(&self) -> String {
+        format!(
+            "{}/{}",
+            self.remote(),
+            self.branch.as_deref().unwrap_or("REMOTE_HEAD")
+        )
+    }
+}
+
+/// Where a repo was listed, for messages.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Location {
+    pub file: String,
+    /// one-based
+    pub line: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+/// Something wrong with a `.gitgeoff` which parses.
+pub struct Problem {
+    pub at: Location,
+    pub message: String,
+    /// bad enough to refuse to do anything, like two repos in one directory
+    pub fatal: bool,
+}
+
+/// The workspace `enter` found.
+struct Workspace {
+    /// the config file, relative to the workspace root, which is now the current directory
+    file: String,
+    /// where we were run from, relative to the root, to only act on the repos there
+    here: Option<PathBuf>,
+}
+
+static WORKSPACE: OnceLock<Workspace> = OnceLock::new();
+
+/// Find the config: `explicit`ly from `--config`, from `$GITGEOFF_CONFIG`, or the nearest
+/// `.gitgeoff` in this directory or a parent, like git finds `.git`. Then move to its
+/// directory, so `local_dir`s are relative to it.
+///
+/// Unless `all`, `load_matching` will only return the repos around where we were run from.
+pub fn enter(explicit: Option<&Path>, all: bool) -> Result<(), Error> {
+    let cwd = fs::canonicalize(env::current_dir()?)?;
+    let from_env = env::var_os("GITGEOFF_CONFIG").map(PathBuf::from);
+    let file = locate(&cwd, explicit, from_env)?;
+
+    let root = file
+        .parent()
+        .ok_or_else(|| anyhow!("config has no directory: {:?}", file))?;
+    let name = file
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow!("unsupported config name: {:?}", file))?;
+    env::set_current_dir(root).with_context(|| anyhow!("entering {:?}", root))?;
+
+    let here = match cwd.strip_prefix(root) {
+        Ok(here) if !all && !here.as_os_str().is_empty() => Some(here.to_path_buf()),
+        _ => None,
+    };
+
+    let workspace = Workspace {
+        file: name.to_string(),
+        here,
+    };
+    if WORKSPACE.set(workspace).is_err() {
+        bail!("already in a workspace");
+    }
+    Ok(())
+}
+
+/// The config file for `enter`, as an absolute path.
+///
+/// Its directory is resolved, but a config which is a link stays in the directory it's in,
+/// however it was found.
+fn locate(
+    cwd: &Path,
+    explicit: Option<&Path>,
+    from_env: Option<PathBuf>,
+) -> Result<PathBuf, Error> {
+    let file = match explicit.map(Path::to_path_buf).or(from_env) {
+        Some(file) => {
+            let file = cwd.join(file);
+            ensure!(file.is_file(), "no config at {:?}", file);
+            file
+        }
+        None => cwd
+            .ancestors()
+            .map(|dir| dir.join(".gitgeoff"))
+            .find(|file| file.is_file())
+            .ok_or_else(|| anyhow!("no .gitgeoff in {:?} or any parent", cwd))?,
+    };
+    let (dir, name) = file
+        .parent()
+        .zip(file.file_name())
+        .ok_or_else(|| anyhow!("config isn't a file in a directory: {:?}", file))?;
+    let dir = fs::canonicalize(dir).with_context(|| anyhow!("resolving {:?}", dir))?;
+    Ok(dir.join(name))
+}
+
+/// The config file's name, in the current directory.
+fn file() -> &'static str {
+    WORKSPACE
+        .get()
+        .map(|workspace| workspace.file.as_str())
+        .unwrap_or(".gitgeoff")
 }
 
+/// Load the `.gitgeoff`, keeping only the enabled repos selected by `--tags`.
+///
+/// If we were run from below the workspace root, only the repo we're in, or those below us,
+/// are selected.
+pub fn load_matching(filter: &TagFilter) -> Result<Vec<Spec>, Error> {
+    let mut specs = load()?;
+    specs.retain(|spec| !spec.disabled && filter.matches(&spec.tags));
+    match WORKSPACE.get().and_then(|w| w.here.as_deref()) {
+        Some(here) => around(specs, here),
+        None => Ok(specs),
+    }
+}
+
+/// The repos containing `here`, or inside it, failing if there are none.
+fn around(mut specs: Vec<Spec>, here: &Path) -> Result<Vec<Spec>, Error> {
+    let near = |spec: &Spec| {
+        spec.local_dir()
+            .is_ok_and(|dir| here.starts_with(dir) || Path::new(dir).starts_with(here))
+    };
+    ensure!(
+        specs.iter().any(near),
+        "no repos in {:?}, or around it; run from the workspace root, or use --all",
+        here
+    );
+    specs.retain(near);
+    Ok(specs)
+}
+
+/// Every repo in the `.gitgeoff`, including the disabled ones.
+///
+/// Fails on any fatal `Problem`, and logs the rest.
 pub fn load() -> Result<Vec<Spec>, Error> {
+    let specs = read(file())?;
+    let mut fatal = 0;
+    for problem in problems(&specs) {
+        if problem.fatal {
+            fatal += 1;
+            error!("{}: {}", problem.at, problem.message);
+        } else {
+            warn!("{}: {}", problem.at, problem.message);
+        }
+    }
+    ensure!(
+        0 == fatal,
+        "{} problems in {} (see `gitgeoff check`)",
+        fatal,
+        file()
+    );
+    Ok(specs)
+}
+
+/// List every `Problem` with the `.gitgeoff`, failing if any are fatal.
+pub fn check() -> Result<(), Error> {
+    let specs = read(file())?;
+    let problems = problems(&specs);
+    for problem in &problems {
+        let severity = if problem.fatal { "error" } else { "warning" };
+        println!("{}: {}: {}", problem.at, severity, problem.message);
+    }
+
+    let fatal = problems.iter().filter(|problem| problem.fatal).count();
+    ensure!(0 == fatal, "{} errors in {}", fatal, file());
+    println!(
+        "{} repos ({} disabled), {} warnings",
+        specs.len(),
+        specs.iter().filter(|spec| spec.disabled).count(),
+        problems.len()
+    );
+    Ok(())
+}
+
+/// The config at `path`, and everything it includes, with repeats merged.
+fn read(path: &str) -> Result<Vec<Spec>, Error> {
+    let mut loader = Loader::default();
+    loader.load(Source::File(PathBuf::from(path)), &HashSet::new())?;
+    Ok(dedupe(loader.specs))
+}
+
+/// Somewhere a config can be read from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Source {
+    /// relative to the workspace root
+    File(PathBuf),
+    /// a file in a managed repo, as of its upstream, so everyone sees the same version
+    Blob {
+        dir: String,
+        rev: String,
+        path: String,
+    },
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::File(path) => write!(f, "{}", path.display()),
+            Source::Blob { dir, rev, path } => write!(f, "{}@{}:{}", dir, rev, path),
+        }
+    }
+}
+
+impl Source {
+    fn read(&self) -> Result<String, Error> {
+        match self {
+            Source::File(path) => {
+                let mut config = String::new();
+                open(path)?
+                    .read_to_string(&mut config)
+                    .with_context(|| anyhow!("reading {:?}", path))?;
+                Ok(config)
+            }
+            Source::Blob { dir, rev, path } => {
+                let repo = git2::Repository::open(dir)?;
+                let blob = repo
+                    .revparse_single(&format!("{}:{}", rev, path))?
+                    .peel_to_blob()?;
+                Ok(String::from_utf8(blob.content().to_vec())
+                    .with_context(|| anyhow!("reading {}", self))?)
+            }
+        }
+    }
+
+    /// `relative` to the directory this is in, e.g. in the same repo.
+    fn sibling(&self, relative: &str) -> Result<Source, Error> {
+        Ok(match self {
+            Source::File(path) => Source::File(match path.parent() {
+                Some(dir) => dir.join(relative),
+                None => PathBuf::from(relative),
+            }),
+            Source::Blob { dir, rev, path } => {
+                ensure!(
+                    !relative.starts_with('/') && !relative.split('/').any(|part| part == ".."),
+                    "can't include outside of {}",
+                    dir
+                );
+                Source::Blob {
+                    dir: dir.to_string(),
+                    rev: rev.to_string(),
+                    path: match path.rfind('/') {
+                        Some(slash) => format!("{}/{}", &path[..slash], relative),
+                        None => relative.to_string(),
+                    },
+                }
+            }
+        })
+    }
+
+    /// The same file, however it was reached, for spotting cycles.
+    fn identity(&self) -> Source {
+        match self {
+            Source::File(path) => Source::File(fs::canonicalize(path).unwrap_or(path.clone())),
+            blob => blob.clone(),
+        }
+    }
+}
+
+/// Reads a config, and everything it `include`s, in order.
+///
+/// An include is a path relative to the including file, or `<url>//<path>` for a file in a
+/// repo listed earlier, e.g. `include https://github.com/FauxFaux/team//repos.gitgeoff team`.
+/// The tags after it are added to every repo it includes.
+#[derive(Default)]
+struct Loader {
+    specs: Vec<Spec>,
+    /// the includes being read, outermost first
+    stack: Vec<Source>,
+}
+
+impl Loader {
+    fn load(&mut self, source: Source, tags: &HashSet<String>) -> Result<(), Error> {
+        let identity = source.identity();
+        if self.stack.iter().any(|outer| outer.identity() == identity) {
+            let mut cycle = self.stack.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+            cycle.push(source.to_string());
+            bail!("include cycle: {}", cycle.join(" -> "));
+        }
+
+        let config = source.read()?;
+        let entries = parse(&source.to_string(), &config)?;
+        self.stack.push(source);
+        for entry in entries {
+            match entry {
+                Line::Repo(mut spec) => {
+                    spec.tags.extend(tags.iter().cloned());
+                    self.specs.push(spec);
+                }
+                Line::Include {
+                    at,
+                    source,
+                    tags: more,
+                } => {
+                    let included = match self
+                        .resolve(&source)
+                        .with_context(|| anyhow!("{}: include {:?}", at, source))?
+                    {
+                        Some(included) => included,
+                        None => {
+                            warn!("{}: {:?} isn't cloned or fetched yet, skipped", at, source);
+                            continue;
+                        }
+                    };
+                    let tags = tags.union(&more).cloned().collect();
+                    self.load(included, &tags)
+                        .with_context(|| anyhow!("{}: including {:?}", at, source))?;
+                }
+            }
+        }
+        self.stack.pop();
+        Ok(())
+    }
+
+    /// `None` if it's in a repo which hasn't been cloned, or fetched since it was added.
+    fn resolve(&self, source: &str) -> Result<Option<Source>, Error> {
+        let (url, path) = match split_repo_source(source) {
+            Some(split) => split,
+            None => {
+                ensure!(
+                    GitUrl::from_str(source).is_err(),
+                    "can't include a url directly, list the repo it's in, then include \
+                    `<repo url>//<path in the repo>`"
+                );
+                let current = self.stack.last().expect("inside a load");
+                return Ok(Some(current.sibling(source)?));
+            }
+        };
+
+        let url = GitUrl::from_str(url)?;
+        let spec = self
+            .specs
+            .iter()
+            .find(|spec| spec.url.normalized() == url.normalized())
+            .ok_or_else(|| anyhow!("{} must be listed before it's included from", url.as_str()))?;
+        let dir = spec.local_dir()?;
+        let rev = spec.upstream();
+        let fetched = match git2::Repository::open(dir) {
+            Ok(repo) => {
+                git::if_found(repo.revparse_single(&format!("{}:{}", rev, path)))?.is_some()
+            }
+            Err(ref e) if e.code() == git2::ErrorCode::NotFound => false,
+            Err(e) => Err(e)?,
+        };
+        Ok(fetched.then(|| Source::Blob {
+            dir: dir.to_string(),
+            rev,
+            path: path.to_string(),
+        }))
+    }
+}
+
+/// `https://example.com/repo//path/in/repo` into the url and the path, if it's a repo at all.
+fn split_repo_source(source: &str) -> Option<(&str, &str)> {
+    let (split, _) = source
+        .match_indices("//")
+        .filter(|&(pos, _)| !source[..pos].ends_with(':'))
+        .last()?;
+    let (url, path) = (&source[..split], &source[split + 2..]);
+    if path.is_empty() || GitUrl::from_str(url).is_err() {
+        return None;
+    }
+    Some((url, path))
+}
+
+/// Merge repos listed more than once, e.g. by overlapping includes, combining their tags.
+/// Any with different options are kept, for `problems` to complain about.
+fn dedupe(specs: Vec<Spec>) -> Vec<Spec> {
+    let mut ret: Vec<Spec> = Vec::with_capacity(specs.len());
+    let mut first: HashMap<String, usize> = HashMap::with_capacity(specs.len());
+    for spec in specs {
+        match first.entry(spec.url.normalized()) {
+            Entry::Occupied(first) => {
+                let first = &mut ret[*first.get()];
+                if first.same_options(&spec) {
+                    first.tags.extend(spec.tags);
+                    first.repeats.push(spec.defined);
+                    first.repeats.extend(spec.repeats);
+                } else {
+                    ret.push(spec);
+                }
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(ret.len());
+                ret.push(spec);
+            }
+        }
+    }
+    ret
+}
+
+/// Repos listed twice with different options, or checked out to the same place, and tags
+/// `--tags` can't select.
+fn problems(specs: &[Spec]) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut urls = HashMap::with_capacity(specs.len());
+    let mut dirs: HashMap<&str, &Spec> = HashMap::with_capacity(specs.len());
+    for spec in specs {
+        let mut problem = |message: String, fatal: bool| {
+            problems.push(Problem {
+                at: spec.defined.clone(),
+                message,
+                fatal,
+            })
+        };
+
+        match urls.entry(spec.url.normalized()) {
+            Entry::Occupied(first) => {
+                problem(
+                    format!(
+                        "{} is already listed at {}, with different options",
+                        spec.url.as_str(),
+                        first.get()
+                    ),
+                    true,
+                );
+                continue;
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(&spec.defined);
+            }
+        }
+
+        match spec.local_dir() {
+            Ok(dir) => match dirs.entry(dir) {
+                Entry::Occupied(first) => {
+                    let first = first.get();
+                    problem(
+                        format!(
+                            "{} would be checked out to {:?}, like {} at {}",
+                            spec.url.as_str(),
+                            dir,
+                            first.url.as_str(),
+                            first.defined
+                        ),
+                        true,
+                    );
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(spec);
+                }
+            },
+            Err(e) => problem(format!("{:#}", e), true),
+        }
+
+        let mut tags = spec.tags.iter().collect::<Vec<_>>();
+        tags.sort_unstable();
+        for tag in tags {
+            if !tags::is_valid(tag) {
+                problem(format!("tag {:?} can't be selected by --tags", tag), false);
+            }
+        }
+    }
+    problems
+}
+
+/// Each repo is either a `[[repo]]` table, in a subset of TOML:
+///
+/// ```text
+/// [[repo]]
+/// url = "https://github.com/FauxFaux/gitgeoff"
+/// tags = ["tools", "rust"]  # optional, like all but the url
+/// path = "geoff"
+/// ```
+///
+/// ..or the shorthand, a url then its tags: `https://github.com/FauxFaux/gitgeoff tools rust`.
+///
+/// `include <source> [tags..]` reads another config, see `Loader`.
+///
+/// `#` starts a comment. Errors name the `file` and line.
+fn parse(file: &str, config: &str) -> Result<Vec<Line>, Error> {
     let mut ret = Vec::with_capacity(20);
-    let file = io::BufReader::new(open(".gitgeoff")?);
-    for line in file.lines() {
-        let line = line?;
-        if line.is_empty() {
+    // the `[[repo]]` we're in, if any
+    let mut table: Option<Table> = None;
+    for (number, line) in config.lines().enumerate() {
+        let at = Location {
+            file: file.to_string(),
+            line: number + 1,
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
             continue;
         }
-        let mut parts = line.split(|c: char| c.is_whitespace());
-        let line = parts.next().ok_or_else(|| anyhow!("invalid config line"))?;
 
-        let url =
-            GitUrl::from_str(line).with_context(|| anyhow!("parsing config line {:?}", line))?;
-        let mut tags = HashSet::with_capacity(4);
-        for tag in parts {
-            tags.insert(tag.to_string());
+        if is_table_header(line) {
+            let new = Table {
+                at,
+                entries: Vec::new(),
+            };
+            if let Some(table) = table.replace(new) {
+                ret.push(Line::Repo(table.into_spec()?));
+            }
+            continue;
+        }
+
+        if let Some((key, value)) = key_value(line) {
+            let table = table
+                .as_mut()
+                .ok_or_else(|| anyhow!("{}: {:?} outside of a [[repo]]", at, key))?;
+            let value =
+                Value::parse(value).with_context(|| anyhow!("{}: parsing {:?}", at, key))?;
+            table.entries.push((at, key, value));
+            continue;
         }
-        ret.push(Spec { url, tags });
+
+        if let Some(table) = table.take() {
+            ret.push(Line::Repo(table.into_spec()?));
+        }
+        ret.push(match line.strip_prefix("include") {
+            Some(rest) if rest.starts_with(char::is_whitespace) => from_include(rest, at)?,
+            _ => Line::Repo(from_shorthand(line, at)?),
+        });
+    }
+
+    if let Some(table) = table {
+        ret.push(Line::Repo(table.into_spec()?));
     }
 
     Ok(ret)
 }
 
+fn is_comment(rest: &str) -> bool {
+    rest.is_empty() || rest.starts_with('#')
+}
+
+/// A line of a config.
+enum Line {
+    Repo(Spec),
+    Include {
+        at: Location,
+        source: String,
+        /// added to every repo included
+        tags: HashSet<String>,
+    },
+}
+
+fn from_include(rest: &str, at: Location) -> Result<Line, Error> {
+    let mut parts = rest
+        .split_whitespace()
+        .take_while(|part| !part.starts_with('#'));
+    let source = parts
+        .next()
+        .ok_or_else(|| anyhow!("{}: include of nothing", at))?
+        .to_string();
+    let tags = parts.map(|tag| tag.to_string()).collect();
+    Ok(Line::Include { at, source, tags })
+}
+
+fn from_shorthand(line: &str, at: Location) -> Result<Spec, Error> {
+    let mut parts = line
+        .split_whitespace()
+        .take_while(|part| !part.starts_with('#'));
+    let url = parts
+        .next()
+        .ok_or_else(|| anyhow!("{}: invalid config line", at))?;
+    let url = GitUrl::from_str(url).with_context(|| anyhow!("{}: parsing url {:?}", at, url))?;
+    let tags = parts.map(|tag| tag.to_string()).collect();
+    Ok(Spec::new(url, tags, at))
+}
+
+/// A `[[repo]]`, and the keys set in it.
+struct Table<'c> {
+    at: Location,
+    entries: Vec<(Location, &'c str, Value)>,
+}
+
+impl Table<'_> {
+    fn into_spec(self) -> Result<Spec, Error> {
+        let mut url = None;
+        let mut tags = HashSet::new();
+        let mut path = None;
+        let mut branch = None;
+        let mut remote = None;
+        let mut depth = None;
+        let mut disabled = false;
+        let mut seen = HashSet::with_capacity(self.entries.len());
+        for (at, key, value) in self.entries {
+            ensure!(
+                seen.insert(key),
+                "{}: {:?} set twice in a [[repo]]",
+                at,
+                key
+            );
+            let set = || -> Result<(), Error> {
+                match key {
+                    "url" => {
+                        let value = value.string(key)?;
+                        url = Some(
+                            GitUrl::from_str(&value)
+                                .with_context(|| anyhow!("parsing url {:?}", value))?,
+                        );
+                    }
+                    "tags" => tags = value.strings(key)?.into_iter().collect(),
+                    "path" => path = Some(checkout_path(value.string(key)?)?),
+                    "branch" => branch = Some(value.string(key)?),
+                    "remote" => remote = Some(value.string(key)?),
+                    "depth" => {
+                        depth = Some(
+                            u32::try_from(value.integer(key)?)
+                                .ok()
+                                .filter(|&depth| depth > 0)
+                                .ok_or_else(|| anyhow!("depth must be a positive number"))?,
+                        )
+                    }
+                    "disabled" => disabled = value.boolean(key)?,
+                    other => bail!("unknown key {:?} in a [[repo]]", other),
+                }
+                Ok(())
+            };
+            set().with_context(|| anyhow!("{}", at))?;
+        }
+
+        Ok(Spec {
+            url: url.ok_or_else(|| anyhow!("{}: [[repo]] without a url", self.at))?,
+            tags,
+            path,
+            branch,
+            remote,
+            depth,
+            disabled,
+            defined: self.at,
+            repeats: Vec::new(),
+        })
+    }
+}
+
+/// A `path` must be somewhere inside the workspace, other than the workspace itself.
+fn checkout_path(path: String) -> Result<String, Error> {
+    let components = Path::new(&path).components().collect::<Vec<_>>();
+    ensure!(
+        !components.is_empty() && components.iter().all(|c| matches!(c, Component::Normal(_))),
+        "path must be a directory inside the workspace, like `dir` or `team/dir`, not {:?}",
+        path
+    );
+    Ok(path)
+}
+
+/// `key = value`, for a bare TOML key, which a url can't look like.
+fn key_value(line: &str) -> Option<(&str, &str)> {
+    let (key, value) = line.split_once('=')?;
+    let key = key.trim_end();
+    if key.is_empty()
+        || !key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return None;
+    }
+    Some((key, value.trim_start()))
+}
+
+/// The TOML values we understand.
+#[derive(Debug, PartialEq)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    /// A whole value, with nothing but whitespace after it.
+    fn parse(s: &str) -> Result<Value, Error> {
+        let (value, rest) = Value::parse_prefix(s)?;
+        ensure!(
+            is_comment(rest.trim()),
+            "unexpected {:?} after value",
+            rest.trim()
+        );
+        Ok(value)
+    }
+
+    /// A value from the start of `s`, and whatever is left after it.
+    fn parse_prefix(s: &str) -> Result<(Value, &str), Error> {
+        let s = s.trim_start();
+        if let Some(rest) = s.strip_prefix('"') {
+            let mut value = String::new();
+            let mut chars = rest.char_indices();
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '"' => return Ok((Value::String(value), &rest[i + 1..])),
+                    '\\' => value.push(match chars.next().map(|(_, c)| c) {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        other => bail!("unsupported escape: {:?}", other),
+                    }),
+                    c => value.push(c),
+                }
+            }
+            bail!("unterminated string");
+        }
+
+        if let Some(rest) = s.strip_prefix('\'') {
+            let end = rest
+                .find('\'')
+                .ok_or_else(|| anyhow!("unterminated string"))?;
+            return Ok((Value::String(rest[..end].to_string()), &rest[end + 1..]));
+        }
+
+        if let Some(mut rest) = s.strip_prefix('[') {
+            let mut values = Vec::new();
+            loop {
+                rest = rest.trim_start();
+                if let Some(rest) = rest.strip_prefix(']') {
+                    return Ok((Value::Array(values), rest));
+                }
+                let (value, after) = Value::parse_prefix(rest)?;
+                values.push(value);
+                rest = after.trim_start();
+                if let Some(after) = rest.strip_prefix(',') {
+                    rest = after;
+                } else if !rest.starts_with(']') {
+                    bail!("expected ',' or ']' in array");
+                }
+            }
+        }
+
+        let end = s
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '+' || c == '_'))
+            .unwrap_or(s.len());
+        let (word, rest) = s.split_at(end);
+        Ok((
+            match word {
+                "true" => Value::Boolean(true),
+                "false" => Value::Boolean(false),
+                word => Value::Integer(
+                    word.replace('_', "")
+                        .parse()
+                        .map_err(|_| anyhow!("unsupported value: {:?}", s))?,
+                ),
+            },
+            rest,
+        ))
+    }
+
+    fn string(self, key: &str) -> Result<String, Error> {
+        match self {
+            Value::String(s) => Ok(s),
+            other => bail!("{:?} must be a string, not {:?}", key, other),
+        }
+    }
+
+    fn strings(self, key: &str) -> Result<Vec<String>, Error> {
+        match self {
+            Value::Array(values) => values.into_iter().map(|v| v.string(key)).collect(),
+            other => bail!("{:?} must be an array of strings, not {:?}", key, other),
+        }
+    }
+
+    fn integer(self, key: &str) -> Result<i64, Error> {
+        match self {
+            Value::Integer(i) => Ok(i),
+            other => bail!("{:?} must be a number, not {:?}", key, other),
+        }
+    }
+
+    fn boolean(self, key: &str) -> Result<bool, Error> {
+        match self {
+            Value::Boolean(b) => Ok(b),
+            other => bail!("{:?} must be true or false, not {:?}", key, other),
+        }
+    }
+}
+
+/// Add a repo to the end of `.gitgeoff`.
+pub fn append(url: &GitUrl, tags: &[&str]) -> Result<(), Error> {
+    let mut config = read_root()?;
+    if !config.is_empty() && !config.ends_with('\n') {
+        config.push('\n');
+    }
+    config.push_str(&shorthand(url.as_str(), tags));
+    config.push('\n');
+    write_root(&config)
+}
+
+/// Take a repo, or all of its `[[repo]]`, out of `.gitgeoff`, leaving everything else alone.
+pub fn remove(spec: &Spec) -> Result<(), Error> {
+    let config = read_root()?;
+    write_root(&without(&config, &editable(spec)?))
+}
+
+/// `config` without the repos defined at each of the sorted `starts`.
+fn without(config: &str, starts: &[usize]) -> String {
+    let mut lines = config.lines().collect::<Vec<_>>();
+    // from the bottom, so the earlier starts stay put
+    for &start in starts.iter().rev() {
+        lines.drain(extent(&lines, start));
+        // don't leave a double gap where it was
+        let blank = |i: usize| lines.get(i).is_some_and(|line| line.trim().is_empty());
+        if start > 0 && blank(start - 1) && (blank(start) || start == lines.len()) {
+            lines.remove(start - 1);
+        }
+    }
+    unlines(&lines, config)
+}
+
+/// Add and remove a repo's tags in `.gitgeoff`, keeping the order of the others.
+///
+/// Returns the new tags.
+pub fn retag(spec: &Spec, add: &[&str], remove: &[&str]) -> Result<Vec<String>, Error> {
+    let config = read_root()?;
+    let (config, tags) = retagged(&config, &editable(spec)?, add, remove)?;
+    write_root(&config)?;
+    Ok(tags)
+}
+
+/// `config` with the tags changed on each of the repos defined at the sorted `starts`, and
+/// all the tags they now have between them.
+fn retagged(
+    config: &str,
+    starts: &[usize],
+    add: &[&str],
+    remove: &[&str],
+) -> Result<(String, Vec<String>), Error> {
+    let mut lines = config.lines().map(str::to_string).collect::<Vec<_>>();
+    let mut each = Vec::with_capacity(starts.len());
+    // from the bottom, so inserting a `tags` doesn't move the earlier starts
+    for &start in starts.iter().rev() {
+        each.push(retag_at(&mut lines, start, add, remove)?);
+    }
+    let mut all: Vec<String> = Vec::new();
+    for tag in each.into_iter().rev().flatten() {
+        if !all.contains(&tag) {
+            all.push(tag);
+        }
+    }
+    Ok((unlines(&lines, config), all))
+}
+
+/// Change the tags of the repo defined at `start`, returning its new tags.
+fn retag_at(
+    lines: &mut Vec<String>,
+    start: usize,
+    add: &[&str],
+    remove: &[&str],
+) -> Result<Vec<String>, Error> {
+    let change = |old: Vec<String>| {
+        let mut tags = old
+            .into_iter()
+            .filter(|tag| !remove.contains(&tag.as_str()))
+            .collect::<Vec<_>>();
+        for tag in add {
+            if !tags.iter().any(|old| old == tag) {
+                tags.push(tag.to_string());
+            }
+        }
+        tags
+    };
+
+    let extent = extent(lines, start);
+    Ok(if extent.len() == 1 && !is_table_header(&lines[start]) {
+        let line = &lines[start];
+        // keep any comment, and the space before it
+        let comment = line
+            .char_indices()
+            .find(|&(i, c)| c == '#' && line[..i].ends_with(char::is_whitespace))
+            .map(|(i, _)| line[..i].trim_end().len())
+            .unwrap_or(line.len());
+        let (body, comment) = line.split_at(comment);
+        let mut parts = body.split_whitespace();
+        let url = parts
+            .next()
+            .ok_or_else(|| anyhow!("empty line {}", start + 1))?;
+        let tags = change(parts.map(str::to_string).collect());
+        lines[start] = format!("{}{}", shorthand(url, &tags), comment);
+        tags
+    } else {
+        let existing = extent
+            .clone()
+            .find(|&i| key_value(lines[i].trim()).is_some_and(|(key, _)| key == "tags"));
+        match existing {
+            Some(i) => {
+                let line = &lines[i];
+                let (_, value) = key_value(line.trim()).expect("found above");
+                let (value, rest) = Value::parse_prefix(value)?;
+                let tags = change(value.strings("tags")?);
+                let indent = &line[..line.len() - line.trim_start().len()];
+                lines[i] = format!("{}tags = {}{}", indent, toml_strings(&tags), rest);
+                tags
+            }
+            None => {
+                let tags = change(Vec::new());
+                lines.insert(start + 1, format!("tags = {}", toml_strings(&tags)));
+                tags
+            }
+        }
+    })
+}
+
+/// The indexes of the lines defining `spec`, sorted, which must all be in `.gitgeoff`, not in
+/// an include.
+fn editable(spec: &Spec) -> Result<Vec<usize>, Error> {
+    let mut starts = Vec::with_capacity(1 + spec.repeats.len());
+    for at in std::iter::once(&spec.defined).chain(&spec.repeats) {
+        ensure!(
+            at.file == file(),
+            "{} is listed in {}, so edit it there",
+            spec.url.as_str(),
+            at.file
+        );
+        starts.push(at.line - 1);
+    }
+    starts.sort_unstable();
+    Ok(starts)
+}
+
+fn is_table_header(line: &str) -> bool {
+    line.trim()
+        .strip_prefix("[[repo]]")
+        .is_some_and(|rest| is_comment(rest.trim()))
+}
+
+/// The lines of the repo defined at `start`: one, or a `[[repo]]` up to its last key.
+fn extent<S: AsRef<str>>(lines: &[S], start: usize) -> Range<usize> {
+    let mut end = start;
+    if is_table_header(lines[start].as_ref()) {
+        for (i, line) in lines.iter().enumerate().skip(start + 1) {
+            let line = line.as_ref().trim();
+            if key_value(line).is_some() {
+                end = i;
+            } else if !is_comment(line) {
+                break;
+            }
+        }
+    }
+    start..end + 1
+}
+
+fn shorthand<S: AsRef<str>>(url: &str, tags: &[S]) -> String {
+    let mut line = url.to_string();
+    for tag in tags {
+        line.push(' ');
+        line.push_str(tag.as_ref());
+    }
+    line
+}
+
+fn toml_strings(strings: &[String]) -> String {
+    let quoted = strings
+        .iter()
+        .map(|s| format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\"")))
+        .collect::<Vec<_>>();
+    format!("[{}]", quoted.join(", "))
+}
+
+/// Join `lines` back up, ending with a newline if the `original` did.
+fn unlines<S: AsRef<str>>(lines: &[S], original: &str) -> String {
+    let mut ret = lines
+        .iter()
+        .map(|line| line.as_ref())
+        .collect::<Vec<_>>()
+        .join("\n");
+    if original.ends_with('\n') && !ret.is_empty() {
+        ret.push('\n');
+    }
+    ret
+}
+
+fn read_root() -> Result<String, Error> {
+    let path = file();
+    fs::read_to_string(path).with_context(|| anyhow!("reading {:?}", path))
+}
+
+fn write_root(config: &str) -> Result<(), Error> {
+    let mut temp = tempfile_fast::Sponge::new_for(file())?;
+    temp.write_all(config.as_bytes())?;
+    temp.commit()?;
+    Ok(())
+}
+
 fn open<P: AsRef<Path>>(path: P) -> Result<fs::File> {
     let path = path.as_ref();
     fs::File::open(path).with_context(|| match fs::canonicalize(path) {
@@ -54,3 +1069,357 @@ fn open<P: AsRef<Path>>(path: P) -> Result<fs::File> {
         Err(_) => anyhow!("resolving {:?}", path),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use anyhow::Error;
+
+    use super::around;
+    use super::dedupe;
+    use super::editable;
+    use super::extent;
+    use super::locate;
+    use super::parse;
+    use super::problems;
+    use super::retagged;
+    use super::split_repo_source;
+    use super::without;
+    use super::Line;
+    use super::Loader;
+    use super::Source;
+    use super::Spec;
+
+    /// The repos in `config`, which mustn't include anything.
+    fn repos(file: &str, config: &str) -> Result<Vec<Spec>, Error> {
+        Ok(parse(file, config)?
+            .into_iter()
+            .map(|line| match line {
+                Line::Repo(spec) => spec,
+                Line::Include { .. } => panic!("unexpected include"),
+            })
+            .collect())
+    }
+
+    #[test]
+    fn tables_and_shorthand() -> Result<(), Error> {
+        let specs = repos(
+            ".gitgeoff",
+            r#"
+# tools
+https://github.com/FauxFaux/gitgeoff tools rust  # the one true
+
+[[repo]]  # moved
+url = "git@github.com:FauxFaux/fapt.git"
+tags = ["debian", 'rust',]  # "quoted"
+path = "fapt-main"
+branch = "next"
+remote = "upstream"
+depth = 10
+
+[[repo]]
+url = "https://github.com/FauxFaux/old"
+disabled = true
+https://github.com/FauxFaux/after
+"#,
+        )?;
+        assert_eq!(4, specs.len());
+
+        assert_eq!("gitgeoff", specs[0].local_dir()?);
+        assert_eq!(2, specs[0].tags.len());
+        assert_eq!(".gitgeoff:3", specs[0].defined.to_string());
+        assert_eq!("origin/REMOTE_HEAD", specs[0].upstream());
+
+        assert_eq!("fapt-main", specs[1].local_dir()?);
+        assert_eq!(2, specs[1].tags.len());
+        assert!(specs[1].tags.contains("rust"));
+        assert_eq!("upstream/next", specs[1].upstream());
+        assert_eq!(Some(10), specs[1].depth);
+        assert_eq!(5, specs[1].defined.line);
+
+        assert!(specs[2].disabled);
+        assert!(!specs[3].disabled);
+        assert_eq!("after", specs[3].local_dir()?);
+
+        let error = |config: &str| match parse("f", config) {
+            Ok(_) => String::new(),
+            Err(e) => format!("{:#}", e),
+        };
+        assert!(error("url = \"https://example.com/a\"").starts_with("f:1: "));
+        assert!(error("[[repo]]\ntags = []").starts_with("f:1: "));
+        assert!(
+            error("#\n[[repo]]\nurl = \"https://example.com/a\"\ncolour = 1").starts_with("f:4: ")
+        );
+        assert!(
+            error("[[repo]]\nurl = \"https://example.com/a\"\ndepth = \"10\"").starts_with("f:3: ")
+        );
+        assert!(error("[[repo]]\nurl = \"https://example.com/a\" x").starts_with("f:2: "));
+        for path in ["", ".", "..", "a/../..", "/tmp/a", "./a"] {
+            let config = format!(
+                "[[repo]]\nurl = \"https://example.com/a\"\npath = {:?}",
+                path
+            );
+            assert!(error(&config).starts_with("f:3: "), "{:?}", path);
+        }
+        let config = "[[repo]]\nurl = \"https://example.com/a\"\npath = \"team/a\"";
+        assert_eq!("", error(config));
+        Ok(())
+    }
+
+    #[test]
+    fn duplicates() -> Result<(), Error> {
+        let specs = dedupe(repos(
+            "f",
+            "https://github.com/a/one first\n\
+             git@github.com:a/one.git second\n\
+             [[repo]]\n\
+             url = \"https://github.com/a/one\"\n\
+             branch = \"next\"\n\
+             https://github.com/b/one\n\
+             [[repo]]\n\
+             url = \"https://github.com/c/one\"\n\
+             path = \"c-one\"\n\
+             https://github.com/a/two !two\n",
+        )?);
+        assert_eq!(2, specs[0].tags.len());
+        assert_eq!(
+            vec![2],
+            specs[0]
+                .repeats
+                .iter()
+                .map(|at| at.line)
+                .collect::<Vec<_>>()
+        );
+        let problems = problems(&specs)
+            .into_iter()
+            .map(|p| (p.at.line, p.fatal))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![(3, true), (6, true), (10, false)], problems);
+        Ok(())
+    }
+
+    #[test]
+    fn includes() -> Result<(), Error> {
+        let lines = parse("f", "include teams/a.gitgeoff backend # ok\ninclude\tb\n")?;
+        match &lines[0] {
+            Line::Include { at, source, tags } => {
+                assert_eq!(1, at.line);
+                assert_eq!("teams/a.gitgeoff", source);
+                assert!(tags.contains("backend") && 1 == tags.len());
+            }
+            Line::Repo(_) => panic!("expected an include"),
+        }
+        assert_eq!(2, lines.len());
+        assert!(parse("f", "include").is_err());
+
+        assert_eq!(
+            Some(("https://github.com/a/b", "repos/x.gitgeoff")),
+            split_repo_source("https://github.com/a/b//repos/x.gitgeoff")
+        );
+        assert_eq!(
+            Some(("git@github.com:a/b.git", "x")),
+            split_repo_source("git@github.com:a/b.git//x")
+        );
+        assert_eq!(None, split_repo_source("https://github.com/a/b"));
+        assert_eq!(None, split_repo_source("teams//a.gitgeoff"));
+        assert_eq!(None, split_repo_source("teams/a.gitgeoff"));
+        Ok(())
+    }
+
+    #[test]
+    fn url_includes() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path().join(".gitgeoff");
+        fs::write(&root, "include https://example.com/team.gitgeoff\n")?;
+        let error = Loader::default()
+            .load(Source::File(root), &Default::default())
+            .expect_err("urls can't be included");
+        assert!(format!("{:#}", error).contains("can't include a url directly"));
+        Ok(())
+    }
+
+    #[test]
+    fn extents() {
+        let lines = [
+            "a tag",
+            "[[repo]]  # b",
+            "url = \"b\"",
+            "# c",
+            "",
+            "tags = []",
+            "",
+            "# d",
+            "d",
+        ];
+        assert_eq!(0..1, extent(&lines, 0));
+        assert_eq!(1..6, extent(&lines, 1));
+        assert_eq!(8..9, extent(&lines, 8));
+    }
+
+    const EDITED: &str = "# team
+
+https://github.com/a/one first  # keep me
+https://github.com/a/two
+
+[[repo]]  # three
+url = \"https://github.com/a/three\"
+tags = [\"x\", \"y\"]  # the tags
+
+[[repo]]
+url = \"https://github.com/a/four\"
+
+git@github.com:a/one.git second
+# end
+";
+
+    /// Where `EDITED` defines the repo named `name`.
+    fn starts(name: &str) -> Result<Vec<usize>, Error> {
+        let specs = dedupe(repos(".gitgeoff", EDITED)?);
+        let spec = specs
+            .iter()
+            .find(|spec| spec.local_dir().ok() == Some(name))
+            .expect("listed");
+        editable(spec)
+    }
+
+    #[test]
+    fn remove() -> Result<(), Error> {
+        assert_eq!(
+            "# team
+
+https://github.com/a/two
+
+[[repo]]  # three
+url = \"https://github.com/a/three\"
+tags = [\"x\", \"y\"]  # the tags
+
+[[repo]]
+url = \"https://github.com/a/four\"
+
+# end
+",
+            without(EDITED, &starts("one")?)
+        );
+        assert_eq!(
+            "# team
+
+https://github.com/a/one first  # keep me
+https://github.com/a/two
+
+[[repo]]
+url = \"https://github.com/a/four\"
+
+git@github.com:a/one.git second
+# end
+",
+            without(EDITED, &starts("three")?)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn retag() -> Result<(), Error> {
+        let (config, tags) = retagged(EDITED, &starts("one")?, &["new"], &["first"])?;
+        assert_eq!(vec!["new", "second"], tags);
+        assert_eq!(
+            "# team
+
+https://github.com/a/one new  # keep me
+https://github.com/a/two
+
+[[repo]]  # three
+url = \"https://github.com/a/three\"
+tags = [\"x\", \"y\"]  # the tags
+
+[[repo]]
+url = \"https://github.com/a/four\"
+
+git@github.com:a/one.git second new
+# end
+",
+            config
+        );
+
+        let (config, tags) = retagged(EDITED, &starts("three")?, &["z"], &["x"])?;
+        assert_eq!(vec!["y", "z"], tags);
+        assert_eq!(EDITED.replace("[\"x\", \"y\"]", "[\"y\", \"z\"]"), config);
+
+        let (config, tags) = retagged(EDITED, &starts("four")?, &["w"], &[])?;
+        assert_eq!(vec!["w"], tags);
+        assert_eq!(
+            EDITED.replace("[[repo]]\nurl", "[[repo]]\ntags = [\"w\"]\nurl"),
+            config
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn locating() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let root = fs::canonicalize(dir.path())?;
+        let deep = root.join("a").join("b");
+        fs::create_dir_all(&deep)?;
+        fs::write(root.join(".gitgeoff"), "")?;
+        fs::write(root.join("a").join("other.gitgeoff"), "")?;
+
+        assert_eq!(root.join(".gitgeoff"), locate(&deep, None, None)?);
+        assert_eq!(root.join(".gitgeoff"), locate(&root, None, None)?);
+
+        let other = root.join("a").join("other.gitgeoff");
+        assert_eq!(other, locate(&deep, None, Some(other.clone()))?);
+        let explicit = root.join(".gitgeoff");
+        assert_eq!(
+            explicit,
+            locate(&deep, Some(&explicit), Some(other.clone()))?
+        );
+        assert!(locate(&deep, None, Some(root.join("missing"))).is_err());
+
+        // relative to where we are
+        assert_eq!(
+            other,
+            locate(&root, Some(Path::new("a/other.gitgeoff")), None)?
+        );
+
+        // a link is in the directory it's in, not its target's
+        #[cfg(unix)]
+        {
+            let linked = root.join("linked");
+            fs::create_dir(&linked)?;
+            std::os::unix::fs::symlink(&other, linked.join(".gitgeoff"))?;
+            assert_eq!(linked.join(".gitgeoff"), locate(&linked, None, None)?);
+            let explicit = linked.join(".gitgeoff");
+            assert_eq!(explicit, locate(&deep, Some(&explicit), None)?);
+            assert_eq!(explicit, locate(&deep, None, Some(explicit.clone()))?);
+        }
+
+        fs::remove_file(root.join(".gitgeoff"))?;
+        assert!(locate(&deep, None, None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn narrowing() -> Result<(), Error> {
+        let specs = repos(
+            "f",
+            "https://example.com/one\n\
+             [[repo]]\n\
+             url = \"https://example.com/two\"\n\
+             path = \"team/two\"\n\
+             https://example.com/three\n",
+        )?;
+        let dirs = |here: &str| -> Result<Vec<String>, Error> {
+            around(specs.clone(), Path::new(here))?
+                .iter()
+                .map(|spec| spec.local_dir().map(str::to_string))
+                .collect()
+        };
+        assert_eq!(vec!["one"], dirs("one")?);
+        assert_eq!(vec!["one"], dirs("one/src/deep")?);
+        assert_eq!(vec!["team/two"], dirs("team")?);
+        assert!(dirs("elsewhere").is_err());
+        assert!(dirs("on").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,334 @@
+use std::env;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Stdio;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use anyhow::Error;
+use git2::Cred;
+use git2::CredentialType;
+use log::debug;
+
+use crate::git_url::GitUrl;
+
+/// Private keys we'll try from `~/.ssh`, in order, if the agent can't help.
+const SSH_KEYS: &[&str] = &["id_ed25519", "id_ecdsa", "id_rsa"];
+
+/// An environment variable holding a token for https remotes..
+const TOKEN_VAR: &str = "GITGEOFF_TOKEN";
+
+/// ..which is only offered to the hosts listed in this one, e.g. `git.example.com,example.org`.
+const TOKEN_HOSTS_VAR: &str = "GITGEOFF_TOKEN_HOSTS";
+
+/// ..and those which are only offered to github.
+const GITHUB_TOKEN_VARS: &[&str] = &["GITHUB_TOKEN", "GH_TOKEN"];
+
+/// Answers libgit2's credential callback for one fetch.
+///
+/// libgit2 calls the callback again every time the remote rejects what we offered, so
+/// each source of credentials is only tried once, and we give up when they run out,
+/// instead of looping forever on the same bad key.
+pub struct Credentials {
+    /// where to run `git credential`, so repo-local config applies
+    dir: PathBuf,
+    username: Option<String>,
+    tried: Vec<Source>,
+    /// what the helper last gave us, until the remote accepts or rejects it
+    filled: Option<Filled>,
+}
+
+/// A username and password from `git credential fill`, for a url.
+struct Filled {
+    url: String,
+    username: String,
+    password: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Source {
+    Username,
+    SshAgent,
+    SshKey(PathBuf),
+    Token(&'static str),
+    Helper,
+    Default,
+}
+
+impl Credentials {
+    pub fn new(dir: &Path, url: &str) -> Credentials {
+        Credentials {
+            dir: dir.to_path_buf(),
+            username: GitUrl::from_str(url)
+                .ok()
+                .and_then(|url| url.username().map(|s| s.to_string())),
+            tried: Vec::new(),
+            filled: None,
+        }
+    }
+
+    /// The fetch worked, so the helper can keep what it gave us, if anything.
+    pub fn accepted(&mut self) {
+        if let Some(filled) = self.filled.take() {
+            filled.tell(&self.dir, "approve");
+        }
+    }
+
+    pub fn next(
+        &mut self,
+        url: &str,
+        username_from_url: Option<&str>,
+        allowed: CredentialType,
+    ) -> Result<Cred, git2::Error> {
+        // being asked again means the remote turned down what we last offered
+        if let Some(filled) = self.filled.take() {
+            filled.tell(&self.dir, "reject");
+        }
+
+        let username = username_from_url
+            .map(|s| s.to_string())
+            .or_else(|| self.username.clone());
+        let token_hosts = env::var(TOKEN_HOSTS_VAR).unwrap_or_default();
+
+        for source in sources(url, allowed, &token_hosts) {
+            if self.tried.contains(&source) {
+                continue;
+            }
+            self.tried.push(source.clone());
+            match self.attempt(&source, url, username.as_deref()) {
+                Ok(Some(cred)) => {
+                    debug!("{}: offering {:?}", url, source);
+                    return Ok(cred);
+                }
+                Ok(None) => (),
+                Err(e) => debug!("{}: {:?} unavailable: {:#}", url, source, e),
+            }
+        }
+
+        Err(git2::Error::from_str(&format!(
+            "no more credentials to try for {} (tried: {:?})",
+            url, self.tried
+        )))
+    }
+
+    fn attempt(
+        &mut self,
+        source: &Source,
+        url: &str,
+        username: Option<&str>,
+    ) -> Result<Option<Cred>, Error> {
+        let ssh_user = username.unwrap_or("git");
+        Ok(Some(match source {
+            Source::Username => Cred::username(ssh_user)?,
+            Source::SshAgent => Cred::ssh_key_from_agent(ssh_user)?,
+            Source::SshKey(path) => Cred::ssh_key(ssh_user, None, path, None)?,
+            Source::Token(var) => match env::var(var) {
+                Ok(token) => {
+                    Cred::userpass_plaintext(username.unwrap_or("x-access-token"), &token)?
+                }
+                Err(_) => return Ok(None),
+            },
+            Source::Helper => match credential_fill(&self.dir, url, username)? {
+                Some(filled) => {
+                    let cred = Cred::userpass_plaintext(&filled.username, &filled.password)?;
+                    self.filled = Some(filled);
+                    cred
+                }
+                None => return Ok(None),
+            },
+            Source::Default => Cred::default()?,
+        }))
+    }
+}
+
+/// What to try, in order; tokens are only for the hosts they're meant for, from the comma
+/// separated `token_hosts`, or github.
+fn sources(url: &str, allowed: CredentialType, token_hosts: &str) -> Vec<Source> {
+    let mut sources = Vec::new();
+    if allowed.contains(CredentialType::USERNAME) {
+        sources.push(Source::Username);
+    }
+    if allowed.contains(CredentialType::SSH_KEY) {
+        sources.push(Source::SshAgent);
+        if let Some(dirs) = directories::UserDirs::new() {
+            sources.extend(
+                SSH_KEYS
+                    .iter()
+                    .map(|name| dirs.home_dir().join(".ssh").join(name))
+                    .filter(|path| path.exists())
+                    .map(Source::SshKey),
+            );
+        }
+    }
+    if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+        let host = url::Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+            .unwrap_or_default();
+        let github = host == "github.com" || host.ends_with(".github.com");
+        let listed = !host.is_empty()
+            && token_hosts
+                .split(',')
+                .any(|listed| listed.trim().eq_ignore_ascii_case(&host));
+        if listed {
+            sources.push(Source::Token(TOKEN_VAR));
+        }
+        if github {
+            sources.extend(GITHUB_TOKEN_VARS.iter().map(|&var| Source::Token(var)));
+        }
+        sources.push(Source::Helper);
+    }
+    if allowed.contains(CredentialType::DEFAULT) {
+        sources.push(Source::Default);
+    }
+    sources
+}
+
+impl Filled {
+    /// Tell the helpers whether this worked, with `git credential approve` or `reject`.
+    fn tell(&self, dir: &Path, action: &str) {
+        let request = format!(
+            "url={}\nusername={}\npassword={}\n\n",
+            self.url, self.username, self.password
+        );
+        match git_credential(dir, action, &request) {
+            Ok(Some(_)) => debug!("{}: credential {}d", self.url, action),
+            Ok(None) => debug!("{}: credential {} failed", self.url, action),
+            Err(e) => debug!("{}: credential {}: {:#}", self.url, action, e),
+        }
+    }
+}
+
+/// Ask `git credential fill`, and hence the user's configured helpers, without prompting.
+fn credential_fill(dir: &Path, url: &str, username: Option<&str>) -> Result<Option<Filled>, Error> {
+    let mut request = format!("url={}\n", url);
+    if let Some(username) = username {
+        request.push_str(&format!("username={}\n", username));
+    }
+    request.push('\n');
+
+    let output = match git_credential(dir, "fill", &request)? {
+        Some(output) => output,
+        None => return Ok(None),
+    };
+
+    let mut username = None;
+    let mut password = None;
+    for line in output.lines() {
+        match line.split_once('=') {
+            Some(("username", value)) => username = Some(value.to_string()),
+            Some(("password", value)) => password = Some(value.to_string()),
+            _ => (),
+        }
+    }
+
+    Ok(username.zip(password).map(|(username, password)| Filled {
+        url: url.to_string(),
+        username,
+        password,
+    }))
+}
+
+/// Run `git credential <action>` on the `request`, returning what it printed, if it worked.
+fn git_credential(dir: &Path, action: &str, request: &str) -> Result<Option<String>, Error> {
+    let mut child = Command::new("git")
+        .args(["credential", action])
+        .current_dir(dir)
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    child
+        .stdin
+        .take()
+        .ok_or_else(|| anyhow!("no stdin"))?
+        .write_all(request.as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    Ok(Some(String::from_utf8(output.stdout)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use anyhow::Error;
+    use git2::CredentialType;
+
+    use super::sources;
+    use super::Credentials;
+    use super::Source;
+
+    #[test]
+    fn tokens_only_for_their_hosts() {
+        let https = CredentialType::USER_PASS_PLAINTEXT;
+        let hosts = "git.example.com, Example.org";
+        assert_eq!(
+            vec![
+                Source::Token("GITHUB_TOKEN"),
+                Source::Token("GH_TOKEN"),
+                Source::Helper
+            ],
+            sources("https://github.com/FauxFaux/gitgeoff", https, hosts)
+        );
+        assert_eq!(
+            vec![Source::Token("GITGEOFF_TOKEN"), Source::Helper],
+            sources("https://git.example.com/FauxFaux/gitgeoff", https, hosts)
+        );
+        assert_eq!(
+            vec![Source::Token("GITGEOFF_TOKEN"), Source::Helper],
+            sources("https://example.org/FauxFaux/gitgeoff", https, hosts)
+        );
+        for elsewhere in [
+            "https://example.com/FauxFaux/gitgeoff",
+            "https://evil.git.example.com/FauxFaux/gitgeoff",
+            "https://evilgithub.com/FauxFaux/gitgeoff",
+            "https://github.com.attacker.net/FauxFaux/gitgeoff",
+        ] {
+            assert_eq!(vec![Source::Helper], sources(elsewhere, https, hosts));
+        }
+        assert_eq!(
+            vec![Source::Helper],
+            sources("https://git.example.com/FauxFaux/gitgeoff", https, "")
+        );
+    }
+
+    #[test]
+    fn helper_hears_how_it_went() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        git2::Repository::init(dir.path())?;
+        let log = dir.path().join("helper.log");
+        let helper = format!(
+            "!f() {{ echo $1 >> '{}'; test $1 = get && echo username=u && echo password=p; true; }}; f",
+            log.display()
+        );
+        let config = dir.path().join(".git").join("config");
+        let mut text = fs::read_to_string(&config)?;
+        // only ours, not any the user has
+        text.push_str(&format!(
+            "[credential]\n\thelper =\n\thelper = {:?}\n",
+            helper
+        ));
+        fs::write(&config, text)?;
+
+        let url = "https://example.invalid/a/b";
+        let https = CredentialType::USER_PASS_PLAINTEXT;
+        let mut rejected = Credentials::new(dir.path(), url);
+        assert!(rejected.next(url, None, https).is_ok());
+        assert!(rejected.next(url, None, https).is_err());
+        rejected.accepted();
+        assert_eq!("get\nerase\n", fs::read_to_string(&log)?);
+
+        fs::remove_file(&log)?;
+        let mut accepted = Credentials::new(dir.path(), url);
+        assert!(accepted.next(url, None, https).is_ok());
+        accepted.accepted();
+        assert_eq!("get\nstore\n", fs::read_to_string(&log)?);
+        Ok(())
+    }
+}
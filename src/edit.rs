@@ -0,0 +1,148 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context;
+use anyhow::Error;
+
+use super::clone;
+use super::config;
+use super::git;
+use super::tags;
+use super::Cache;
+use crate::git_url::GitUrl;
+use config::Spec;
+
+/// Add `url` to `.gitgeoff`, unless it's there already, or would be checked out over another.
+pub fn add(cache: &Cache, url: &str, tags: &[&str], then_clone: bool) -> Result<(), Error> {
+    let url = GitUrl::from_str(url).with_context(|| anyhow!("parsing url {:?}", url))?;
+    check_tags(tags)?;
+    let dir = url.local_dir()?;
+    for spec in config::load()? {
+        ensure!(
+            spec.url.normalized() != url.normalized(),
+            "{} is already listed at {}",
+            url.as_str(),
+            spec.defined
+        );
+        ensure!(
+            spec.local_dir().ok() != Some(dir),
+            "{} would be checked out to {:?}, like {} at {}",
+            url.as_str(),
+            dir,
+            spec.url.as_str(),
+            spec.defined
+        );
+    }
+
+    config::append(&url, tags)?;
+    println!("added {} as {}", url.as_str(), dir);
+
+    if then_clone {
+        let added = config::load()?
+            .into_iter()
+            .filter(|spec| spec.url.normalized() == url.normalized())
+            .collect();
+        clone::clone_specs(cache, added, true)?;
+    }
+    Ok(())
+}
+
+/// Take `name` out of `.gitgeoff`, and, if asked, delete its checkout, if nothing would be lost.
+pub fn remove(name: &str, delete: bool) -> Result<(), Error> {
+    let specs = config::load()?;
+    let spec = find(&specs, name)?;
+    let dir = spec.local_dir()?;
+    let delete = delete && Path::new(dir).exists();
+    if delete {
+        let root = env::current_dir()?;
+        let full = fs::canonicalize(dir)?;
+        ensure!(
+            full.starts_with(&root) && full != root,
+            "not deleting {:?}, it's not inside the workspace",
+            full
+        );
+        ensure_clean(spec).with_context(|| anyhow!("not deleting {:?}", dir))?;
+    }
+
+    config::remove(spec)?;
+    println!("removed {}", spec.url.as_str());
+
+    if delete {
+        fs::remove_dir_all(dir).with_context(|| anyhow!("deleting {:?}", dir))?;
+        println!("deleted {}", dir);
+    }
+    Ok(())
+}
+
+/// Apply `changes` like `+new` (or just `new`) and `-old` to `name`'s tags.
+pub fn tag(name: &str, changes: &[&str]) -> Result<(), Error> {
+    let mut add = Vec::with_capacity(changes.len());
+    let mut remove = Vec::with_capacity(changes.len());
+    for change in changes {
+        match change.strip_prefix('-') {
+            Some(tag) => remove.push(tag),
+            None => add.push(change.strip_prefix('+').unwrap_or(change)),
+        }
+    }
+    check_tags(&add)?;
+
+    let specs = config::load()?;
+    let spec = find(&specs, name)?;
+    let tags = config::retag(spec, &add, &remove)?;
+    println!("{}: {}", spec.local_dir()?, tags.join(" "));
+    Ok(())
+}
+
+/// The repo checked out to `name`, or with `name` as its url.
+fn find<'s>(specs: &'s [Spec], name: &str) -> Result<&'s Spec, Error> {
+    let mut found = specs
+        .iter()
+        .filter(|spec| spec.local_dir().ok() == Some(name) || spec.url.same_repo(name));
+    let spec = found
+        .next()
+        .ok_or_else(|| anyhow!("no repo called {:?} in .gitgeoff", name))?;
+    if let Some(other) = found.next() {
+        bail!(
+            "{:?} could be {} at {}, or {} at {}",
+            name,
+            spec.url.as_str(),
+            spec.defined,
+            other.url.as_str(),
+            other.defined
+        );
+    }
+    Ok(spec)
+}
+
+fn check_tags(tags: &[&str]) -> Result<(), Error> {
+    for tag in tags {
+        ensure!(tags::is_valid(tag), "invalid tag {:?}", tag);
+    }
+    Ok(())
+}
+
+/// No changes, stashes, or branches which aren't on the remote.
+fn ensure_clean(spec: &Spec) -> Result<(), Error> {
+    let mut repo = git2::Repository::open(spec.local_dir()?)?;
+    let changes = git::statuses(&repo)?;
+    ensure!(
+        changes.is_empty(),
+        "uncommitted changes: {}",
+        git::ChangeSummary::of(&changes)
+    );
+    let stashes = git::stashes(&mut repo)?;
+    ensure!(stashes.is_empty(), "{} stashes", stashes.len());
+    for branch in git::branch_states(&repo, &spec.upstream())? {
+        let pushed = match branch.variance {
+            Some(git::Variance::Equal) | Some(git::Variance::Behind(_)) => true,
+            _ => branch.merged == Some(true),
+        };
+        ensure!(pushed, "unpushed branch: {}", branch);
+    }
+    Ok(())
+}
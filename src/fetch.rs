@@ -0,0 +1,90 @@
+use std::collections::BTreeSet;
+use std::env;
+use std::fs;
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Error;
+use log::warn;
+
+use super::git;
+use super::infect;
+use super::progress;
+use super::Cache;
+use crate::config::Spec;
+
+/// Bring `repo`'s view of its remote up to date, through its mirror if it has one.
+pub fn update(
+    spec: &Spec,
+    repo: &git2::Repository,
+    progress: &progress::Repo,
+) -> Result<(), Error> {
+    infect::add_if_missing(repo, spec.remote())?;
+    match git::mirror_of(repo)? {
+        Some(mirror) => {
+            git::clone_or_fetch(spec.url.as_str(), &mirror, |p| progress.update(p))?;
+            git::fetch_from_mirror(repo, spec.remote(), &mirror)?;
+        }
+        None => git::fetch_remote(repo, spec.remote(), |p| progress.update(p))
+            .with_context(|| anyhow!("fetching {:?} -> {:?}", spec.url.as_str(), repo.path()))?,
+    }
+    Ok(())
+}
+
+/// Run `op`, then up to `retries` more times if it fails, backing off between attempts.
+pub fn with_retries<T, F: Fn() -> Result<T, Error>>(
+    retries: u32,
+    label: &str,
+    op: F,
+) -> Result<T, Error> {
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Ok(t) => return Ok(t),
+            Err(e) if attempt < retries => {
+                let delay = backoff(attempt);
+                warn!("{}: retrying in {:?}: {:#}", label, delay, e);
+                thread::sleep(delay);
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn backoff(attempt: u32) -> Duration {
+    Duration::from_secs(1 << attempt.min(5))
+}
+
+/// The urls which failed to update last time, in this workspace.
+pub fn last_failures(cache: &Cache) -> Result<BTreeSet<String>, Error> {
+    let path = cache.failures(&env::current_dir()?)?;
+    match fs::read(&path) {
+        Ok(bytes) => {
+            Ok(serde_json::from_slice(&bytes).with_context(|| anyhow!("reading {:?}", path))?)
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeSet::new()),
+        Err(e) => Err(e)?,
+    }
+}
+
+/// Forget the failures of the urls which were `attempted`, then remember the new `failed`.
+pub fn record_failures<'s>(
+    cache: &Cache,
+    attempted: impl IntoIterator<Item = &'s str>,
+    failed: impl IntoIterator<Item = &'s str>,
+) -> Result<(), Error> {
+    let mut failures = last_failures(cache)?;
+    for url in attempted {
+        failures.remove(url);
+    }
+    failures.extend(failed.into_iter().map(|url| url.to_string()));
+
+    let mut temp = tempfile_fast::Sponge::new_for(cache.failures(&env::current_dir()?)?)?;
+    serde_json::to_writer(&mut temp, &failures)?;
+    temp.commit()?;
+    Ok(())
+}
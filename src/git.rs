@@ -1,15 +1,25 @@
+use std::fmt;
+use std::fs;
+use std::io;
 use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
 
 use anyhow::anyhow;
+use anyhow::bail;
 use anyhow::Context;
 use anyhow::Error;
 use git2::Oid;
 use git2::Remote;
 use git2::Repository;
 use git2::Status;
-use log::info;
+use log::debug;
 
-fn if_found<T>(res: Result<T, git2::Error>) -> Result<Option<T>, Error> {
+use crate::credentials::Credentials;
+use crate::git_url::GitUrl;
+use crate::infect;
+
+pub fn if_found<T>(res: Result<T, git2::Error>) -> Result<Option<T>, Error> {
     match res {
         Ok(t) => Ok(Some(t)),
         Err(ref e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
@@ -17,31 +27,195 @@ fn if_found<T>(res: Result<T, git2::Error>) -> Result<Option<T>, Error> {
     }
 }
 
-pub fn first_statuses(repo: &git2::Repository) -> Result<Vec<String>, Error> {
-    let statuses = repo.statuses(None)?;
+/// What happened to a file, on one side of the index.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, serde_derive::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Added,
+    Modified,
+    Deleted,
+    Renamed,
+    TypeChange,
+    Untracked,
+    Conflicted,
+}
+
+/// A file which differs from `HEAD`, split like `git status --short`: `staged` is the
+/// index vs. `HEAD`, `unstaged` is the working tree vs. the index.
+#[derive(PartialEq, Eq, Clone, Debug, serde_derive::Serialize)]
+pub struct Change {
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old_path: Option<String>,
+    pub staged: Option<ChangeKind>,
+    pub unstaged: Option<ChangeKind>,
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default, serde_derive::Serialize)]
+pub struct ChangeSummary {
+    pub staged: usize,
+    pub unstaged: usize,
+    pub untracked: usize,
+    pub conflicted: usize,
+}
+
+pub fn statuses(repo: &git2::Repository) -> Result<Vec<Change>, Error> {
+    let mut options = git2::StatusOptions::new();
+    options
+        .include_untracked(true)
+        .include_ignored(false)
+        .renames_head_to_index(true)
+        .renames_index_to_workdir(true);
+
+    let statuses = repo.statuses(Some(&mut options))?;
     Ok(statuses
         .iter()
-        .filter(|status| !status.status().is_ignored())
-        .take(3)
-        .map(|status| {
-            format!(
-                "{} {:?}",
-                match status.status() {
-                    Status::INDEX_NEW => "add".to_string(),
-                    Status::WT_NEW => "new".to_string(),
-                    Status::INDEX_MODIFIED | Status::WT_MODIFIED => "mod".to_string(),
-                    Status::INDEX_DELETED | Status::WT_DELETED => "del".to_string(),
-                    Status::INDEX_RENAMED | Status::WT_RENAMED => "mov".to_string(),
-                    Status::CONFLICTED => "CON".to_string(),
-                    other => format!("?{:?}?", other),
-                },
-                status.path().unwrap_or("?")
-            )
+        .filter(|entry| !entry.status().is_ignored())
+        .map(|entry| {
+            let status = entry.status();
+            let renamed = entry
+                .head_to_index()
+                .filter(|_| status.is_index_renamed())
+                .or_else(|| entry.index_to_workdir().filter(|_| status.is_wt_renamed()));
+            let path_of =
+                |file: git2::DiffFile| file.path().map(|p| p.to_string_lossy().to_string());
+            let (path, old_path) = match renamed {
+                Some(delta) => (path_of(delta.new_file()), path_of(delta.old_file())),
+                None => (entry.path().map(|p| p.to_string()), None),
+            };
+
+            let (staged, unstaged) = change_kinds(status);
+            Change {
+                path: path.unwrap_or_else(|| "?".to_string()),
+                old_path,
+                staged,
+                unstaged,
+            }
         })
         .collect())
 }
 
-#[derive(PartialEq, Eq, Clone, Debug)]
+fn change_kinds(status: Status) -> (Option<ChangeKind>, Option<ChangeKind>) {
+    if status.is_conflicted() {
+        return (Some(ChangeKind::Conflicted), Some(ChangeKind::Conflicted));
+    }
+
+    let staged = if status.is_index_new() {
+        Some(ChangeKind::Added)
+    } else if status.is_index_modified() {
+        Some(ChangeKind::Modified)
+    } else if status.is_index_deleted() {
+        Some(ChangeKind::Deleted)
+    } else if status.is_index_renamed() {
+        Some(ChangeKind::Renamed)
+    } else if status.is_index_typechange() {
+        Some(ChangeKind::TypeChange)
+    } else {
+        None
+    };
+
+    let unstaged = if status.is_wt_new() {
+        Some(ChangeKind::Untracked)
+    } else if status.is_wt_modified() {
+        Some(ChangeKind::Modified)
+    } else if status.is_wt_deleted() {
+        Some(ChangeKind::Deleted)
+    } else if status.is_wt_renamed() {
+        Some(ChangeKind::Renamed)
+    } else if status.is_wt_typechange() {
+        Some(ChangeKind::TypeChange)
+    } else {
+        None
+    };
+
+    (staged, unstaged)
+}
+
+impl ChangeKind {
+    /// The letter `git status --short` uses.
+    pub fn code(self) -> char {
+        match self {
+            ChangeKind::Added => 'A',
+            ChangeKind::Modified => 'M',
+            ChangeKind::Deleted => 'D',
+            ChangeKind::Renamed => 'R',
+            ChangeKind::TypeChange => 'T',
+            ChangeKind::Untracked => '?',
+            ChangeKind::Conflicted => 'U',
+        }
+    }
+}
+
+impl Change {
+    pub fn is_untracked(&self) -> bool {
+        self.unstaged == Some(ChangeKind::Untracked)
+    }
+
+    pub fn is_conflicted(&self) -> bool {
+        self.unstaged == Some(ChangeKind::Conflicted)
+    }
+
+    /// e.g. `RM old -> new`, as `git status --short` would show it.
+    pub fn short(&self) -> String {
+        let code = |kind: Option<ChangeKind>| kind.map(ChangeKind::code).unwrap_or(' ');
+        let staged = if self.is_untracked() {
+            '?'
+        } else {
+            code(self.staged)
+        };
+        match &self.old_path {
+            Some(old_path) => format!(
+                "{}{} {} -> {}",
+                staged,
+                code(self.unstaged),
+                old_path,
+                self.path
+            ),
+            None => format!("{}{} {}", staged, code(self.unstaged), self.path),
+        }
+    }
+}
+
+impl ChangeSummary {
+    pub fn of(changes: &[Change]) -> ChangeSummary {
+        let mut summary = ChangeSummary::default();
+        for change in changes {
+            if change.is_conflicted() {
+                summary.conflicted += 1;
+            } else if change.is_untracked() {
+                summary.untracked += 1;
+            } else {
+                if change.staged.is_some() {
+                    summary.staged += 1;
+                }
+                if change.unstaged.is_some() {
+                    summary.unstaged += 1;
+                }
+            }
+        }
+        summary
+    }
+}
+
+impl fmt::Display for ChangeSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let parts = [
+            (self.staged, "staged"),
+            (self.unstaged, "unstaged"),
+            (self.untracked, "untracked"),
+            (self.conflicted, "conflicted"),
+        ];
+        let parts = parts
+            .iter()
+            .filter(|(count, _)| *count > 0)
+            .map(|(count, name)| format!("{} {}", count, name))
+            .collect::<Vec<_>>();
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Debug, serde_derive::Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Variance {
     Equal,
     NotOnBranch,
@@ -55,23 +229,28 @@ pub enum Variance {
     Diverged { local: usize, remote: usize },
 }
 
-pub fn variance_from_origin_head(repo: &git2::Repository) -> Result<Variance, Error> {
+/// Compare `HEAD` to the remote tracking branch `upstream`, e.g. `origin/REMOTE_HEAD`.
+pub fn variance_from_upstream(repo: &git2::Repository, upstream: &str) -> Result<Variance, Error> {
     let head = repo.head()?;
     if !head.is_branch() {
         return Ok(Variance::NotOnBranch);
     }
 
     let local = head.peel_to_commit()?.id();
-    let remote = repo.revparse_single("origin/REMOTE_HEAD")?.id();
+    let remote = repo.revparse_single(upstream)?.id();
 
+    variance_between(repo, local, remote)
+}
+
+fn variance_between(repo: &Repository, local: Oid, remote: Oid) -> Result<Variance, Error> {
     if local == remote {
         return Ok(Variance::Equal);
     }
 
     let base = repo.merge_base(local, remote)?;
 
-    let ahead = commits_in(&repo, local, base)?;
-    let behind = commits_in(&repo, remote, base)?;
+    let ahead = commits_in(repo, local, base)?;
+    let behind = commits_in(repo, remote, base)?;
 
     Ok(if base == remote && 0 != ahead {
         Variance::Ahead(ahead)
@@ -85,52 +264,331 @@ pub fn variance_from_origin_head(repo: &git2::Repository) -> Result<Variance, Er
     })
 }
 
-pub fn clone_or_fetch(url: &str, dest: &Path) -> Result<(), Error> {
-    let repo = match if_found(git2::Repository::open_bare(&dest))? {
-        Some(repository) => repository,
-        None => git2::Repository::init_bare(&dest)?,
+pub fn remote_url(repo: &Repository, remote: &str) -> Result<Option<String>, Error> {
+    Ok(match if_found(repo.find_remote(remote))? {
+        Some(origin) => origin.url().map(|url| url.to_string()),
+        None => None,
+    })
+}
+
+/// A local branch, compared to its upstream and to the branch we follow, usually the remote's
+/// default branch.
+#[derive(PartialEq, Eq, Clone, Debug, serde_derive::Serialize)]
+pub struct BranchState {
+    pub name: String,
+    pub head: bool,
+    /// `None` if there's no upstream configured, or it has gone away.
+    pub upstream: Option<String>,
+    /// An upstream is configured, but it has gone away, e.g. the branch was deleted on merge.
+    pub gone: bool,
+    pub variance: Option<Variance>,
+    /// Whether the followed branch, e.g. `origin/REMOTE_HEAD`, contains this branch, i.e. it's
+    /// safe to delete. `None` for branches tracking it, where it's not interesting.
+    pub merged: Option<bool>,
+}
+
+pub fn branch_states(repo: &Repository, followed: &str) -> Result<Vec<BranchState>, Error> {
+    let default = match if_found(repo.revparse_single(followed))? {
+        Some(default) => Some(default.peel_to_commit()?.id()),
+        None => None,
     };
 
-    let mut origin = match if_found(repo.find_remote("origin"))? {
-        Some(origin) => origin,
-        None => repo.remote("origin", url)?,
+    let config = repo.config()?;
+    let mut ret = Vec::with_capacity(4);
+    for branch in repo.branches(Some(git2::BranchType::Local))? {
+        let (branch, _) = branch?;
+        let name = String::from_utf8_lossy(branch.name_bytes()?).to_string();
+        let tip = branch.get().peel_to_commit()?.id();
+
+        let (upstream, upstream_tip, variance) = match if_found(branch.upstream())? {
+            Some(upstream) => {
+                let upstream_tip = upstream.get().peel_to_commit()?.id();
+                (
+                    Some(String::from_utf8_lossy(upstream.name_bytes()?).to_string()),
+                    Some(upstream_tip),
+                    Some(variance_between(repo, tip, upstream_tip)?),
+                )
+            }
+            None => (None, None, None),
+        };
+        let gone = upstream.is_none()
+            && if_found(config.get_string(&format!("branch.{}.merge", name)))?.is_some();
+
+        let merged = match default {
+            Some(default) if upstream_tip != Some(default) => {
+                Some(tip == default || repo.graph_descendant_of(default, tip)?)
+            }
+            _ => None,
+        };
+
+        ret.push(BranchState {
+            name,
+            head: branch.is_head(),
+            upstream,
+            gone,
+            variance,
+            merged,
+        });
+    }
+
+    Ok(ret)
+}
+
+impl BranchState {
+    /// Worth mentioning: unpushed, untracked, or finished with.
+    pub fn is_notable(&self) -> bool {
+        self.upstream.is_none()
+            || self
+                .variance
+                .as_ref()
+                .is_some_and(|v| *v != Variance::Equal)
+            || self.merged == Some(true)
+    }
+}
+
+impl fmt::Display for BranchState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        let mut notes = Vec::with_capacity(2);
+        match (&self.upstream, &self.variance) {
+            (Some(upstream), Some(variance)) if *variance != Variance::Equal => {
+                notes.push(format!("{:?} of {}", variance, upstream))
+            }
+            (None, _) if self.gone => notes.push("upstream gone".to_string()),
+            (None, _) => notes.push("no upstream".to_string()),
+            _ => (),
+        }
+        if self.merged == Some(true) {
+            notes.push("merged".to_string());
+        }
+        if !notes.is_empty() {
+            write!(f, " ({})", notes.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+pub fn stashes(repo: &mut Repository) -> Result<Vec<String>, Error> {
+    let mut ret = Vec::new();
+    repo.stash_foreach(|index, message, _| {
+        ret.push(format!("stash@{{{}}}: {}", index, message));
+        true
+    })?;
+    Ok(ret)
+}
+
+/// Maintain a bare mirror of all of `url`'s branches at `dest`, with `HEAD` matching
+/// the remote's default branch.
+pub fn clone_or_fetch<F: Fn(Progress)>(url: &str, dest: &Path, progress: F) -> Result<(), Error> {
+    let repo = match if_found(git2::Repository::open_bare(dest))? {
+        Some(repository) => repository,
+        None => git2::Repository::init_bare(dest)?,
     };
 
+    match if_found(repo.find_remote("origin"))? {
+        None => {
+            repo.remote("origin", url)?;
+        }
+        Some(origin) => {
+            // mirrors are keyed on a mangled url, so two repos could land in one
+            let existing = origin.url().unwrap_or("");
+            if existing != url {
+                let same = GitUrl::from_str(url)?.same_repo(existing)
+                    && GitUrl::from_str(existing)?.provider().is_some();
+                if !same {
+                    bail!("mirror {:?} is of {:?}, not {:?}", dest, existing, url);
+                }
+                repo.remote_set_url("origin", url)?;
+            }
+        }
+    }
+
     if_found(repo.config()?.remove_multivar("remote.origin.fetch", ".*"))?;
     repo.remote_add_fetch("origin", "+refs/heads/*:refs/heads/*")?;
+    let mut origin = repo.find_remote("origin")?;
 
-    info!("fetching {:?} -> {:?}", url, dest);
-    do_fetch(&repo, &mut origin, |p| {
-        info!("{:?}: {:?}", dest, p);
-    })
-    .with_context(|| anyhow!("fetching {:?} -> {:?}", url, dest))?;
+    debug!("fetching {:?} -> {:?}", url, dest);
+    do_fetch(&repo, &mut origin, None, progress)
+        .with_context(|| anyhow!("fetching {:?} -> {:?}", url, dest))?;
+
+    if let Some(refname) = if_found(origin.default_branch())? {
+        let refname = refname
+            .as_str()
+            .ok_or_else(|| anyhow!("non-utf-8 default branch"))?;
+        repo.set_head(refname)?;
+    }
 
     Ok(())
 }
 
-pub fn fetch_origin_default(repo: &Repository) -> Result<(), Error> {
-    let mut origin = repo.find_remote("origin")?;
-    do_fetch(&repo, &mut origin, |p| {
-        info!("{:?}", p);
-    })?;
+/// Where to clone from, and what to check out.
+pub struct Upstream<'u> {
+    pub url: &'u str,
+    /// the name of the remote, e.g. `origin`
+    pub remote: &'u str,
+    /// the branch to check out, instead of the remote's default branch
+    pub branch: Option<&'u str>,
+}
+
+/// Clone into a new working directory, tracking the remote's default branch, or `branch`.
+/// `depth` limits how much history is fetched.
+///
+/// Returns the name of the branch checked out, if the remote has one.
+pub fn clone<F: Fn(Progress)>(
+    upstream: &Upstream,
+    depth: Option<u32>,
+    dest: &Path,
+    progress: F,
+) -> Result<Option<String>, Error> {
+    let repo = init_working(upstream, dest)?;
+    // re-read, so the fetch sees the refspec `infect` added
+    let mut remote = repo.find_remote(upstream.remote)?;
+
+    debug!("cloning {:?} -> {:?}", upstream.url, dest);
+    do_fetch(&repo, &mut remote, depth, progress)
+        .with_context(|| anyhow!("fetching {:?} -> {:?}", upstream.url, dest))?;
+
+    checkout_branch(&repo, &remote, upstream)
+}
+
+/// Like `clone`, but refresh the `mirror` (see `clone_or_fetch`), and borrow its objects
+/// through `objects/info/alternates`, so the new clone needs no network and little disk.
+pub fn clone_via_mirror<F: Fn(Progress)>(
+    upstream: &Upstream,
+    mirror: &Path,
+    dest: &Path,
+    progress: F,
+) -> Result<Option<String>, Error> {
+    clone_or_fetch(upstream.url, mirror, progress)?;
+
+    init_working(upstream, dest)?;
+    fs::write(
+        dest.join(".git/objects/info/alternates"),
+        format!("{}\n", mirror.join("objects").display()),
+    )?;
+
+    // re-open, so the object database sees the alternate
+    let repo = Repository::open(dest)?;
+    let from_mirror = fetch_from_mirror(&repo, upstream.remote, mirror)?;
+    checkout_branch(&repo, &from_mirror, upstream)
+}
+
+fn init_working(upstream: &Upstream, dest: &Path) -> Result<Repository, Error> {
+    let repo = Repository::init(dest)?;
+    repo.remote(upstream.remote, upstream.url)?;
+    infect::add_if_missing(&repo, upstream.remote)?;
+    Ok(repo)
+}
+
+/// The bare mirror a working clone is borrowing objects from, if any.
+pub fn mirror_of(repo: &Repository) -> Result<Option<PathBuf>, Error> {
+    let alternates = match fs::read_to_string(repo.path().join("objects/info/alternates")) {
+        Ok(alternates) => alternates,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => Err(e)?,
+    };
+
+    Ok(alternates
+        .lines()
+        .map(Path::new)
+        .find(|objects| objects.ends_with("objects") && objects.is_absolute())
+        .and_then(|objects| objects.parent())
+        .map(|mirror| mirror.to_path_buf()))
+}
+
+/// Update `remote`'s tracking branches from a local `mirror`, which shares our objects, so
+/// this only writes refs.
+pub fn fetch_from_mirror<'r>(
+    repo: &'r Repository,
+    remote: &str,
+    mirror: &Path,
+) -> Result<Remote<'r>, Error> {
+    let mirror_url = mirror
+        .to_str()
+        .ok_or_else(|| anyhow!("non-utf-8 mirror path: {:?}", mirror))?;
+    let mut from_mirror = repo.remote_anonymous(mirror_url)?;
+    from_mirror
+        .fetch(
+            &[
+                format!("+refs/heads/*:refs/remotes/{}/*", remote),
+                format!("+HEAD:refs/remotes/{}/REMOTE_HEAD", remote),
+            ],
+            None,
+            None,
+        )
+        .with_context(|| anyhow!("fetching from mirror {:?}", mirror))?;
+    Ok(from_mirror)
+}
+
+/// Check out `upstream.branch`, or the default branch, which `fetched` must have been
+/// connected to know, e.g. by a fetch.
+fn checkout_branch(
+    repo: &Repository,
+    fetched: &Remote,
+    upstream: &Upstream,
+) -> Result<Option<String>, Error> {
+    let name = match upstream.branch {
+        Some(name) => name.to_string(),
+        None => {
+            let refname = match if_found(fetched.default_branch())? {
+                Some(refname) => refname,
+                // an empty repo, nothing to check out
+                None => return Ok(None),
+            };
+            let refname = refname
+                .as_str()
+                .ok_or_else(|| anyhow!("non-utf-8 default branch"))?;
+            refname
+                .strip_prefix("refs/heads/")
+                .ok_or_else(|| anyhow!("default branch isn't a branch: {:?}", refname))?
+                .to_string()
+        }
+    };
+
+    let tracking = format!("{}/{}", upstream.remote, name);
+    let commit = if_found(repo.find_reference(&format!("refs/remotes/{}", tracking)))?
+        .ok_or_else(|| anyhow!("no branch {:?} on the remote", name))?
+        .peel_to_commit()?;
+    let mut branch = repo.branch(&name, &commit, false)?;
+    branch.set_upstream(Some(&tracking))?;
+
+    repo.set_head(&format!("refs/heads/{}", name))?;
+    repo.checkout_head(Some(git2::build::CheckoutBuilder::new().force()))?;
+
+    Ok(Some(name))
+}
+
+/// Fetch new commits, keeping a shallow clone as shallow as it was.
+pub fn fetch_remote<F: Fn(Progress)>(
+    repo: &Repository,
+    remote: &str,
+    progress: F,
+) -> Result<(), Error> {
+    let mut remote = repo.find_remote(remote)?;
+    do_fetch(repo, &mut remote, None, progress)?;
     Ok(())
 }
 
 #[derive(Debug)]
-enum Progress {
+pub enum Progress {
     Sideband(String),
+    /// local, received, indexed and total objects; indexed and total deltas; received bytes
     Transfer([usize; 7]),
 }
 
 fn do_fetch<F: Fn(Progress)>(
     repo: &Repository,
     origin: &mut Remote,
+    depth: Option<u32>,
     progress: F,
 ) -> Result<(), Error> {
+    let mut credentials = Credentials::new(
+        repo.workdir().unwrap_or_else(|| repo.path()),
+        origin.url().unwrap_or(""),
+    );
     let mut cb = git2::RemoteCallbacks::new();
-    cb.credentials(|_, _, _| {
-        // TODO: do we need to parse this out of the URL, or have it as config?
-        git2::Cred::ssh_key_from_agent("git")
+    cb.credentials(|url, username_from_url, allowed| {
+        credentials.next(url, username_from_url, allowed)
     });
 
     // text from the remote, e.g. "counting objects"
@@ -156,11 +614,17 @@ fn do_fetch<F: Fn(Progress)>(
 
     let mut options = git2::FetchOptions::default();
     options.remote_callbacks(cb);
+    if let Some(depth) = depth {
+        options.depth(i32::try_from(depth)?);
+    }
 
     origin
         .fetch(&[] as &[&str], Some(&mut options), None)
         .with_context(|| "fetching")?;
 
+    // done with the callbacks, and the credentials they borrowed
+    drop(options);
+    credentials.accepted();
     Ok(())
 }
 
@@ -173,6 +637,97 @@ pub fn commits_in(repo: &Repository, start: Oid, end: Oid) -> Result<usize, Erro
 
 #[cfg(test)]
 mod tests {
+    use super::Change;
+    use super::ChangeKind;
+    use super::ChangeSummary;
+
+    #[test]
+    fn short_changes() {
+        let changes = [
+            Change {
+                path: "new".to_string(),
+                old_path: None,
+                staged: None,
+                unstaged: Some(ChangeKind::Untracked),
+            },
+            Change {
+                path: "both".to_string(),
+                old_path: None,
+                staged: Some(ChangeKind::Modified),
+                unstaged: Some(ChangeKind::Modified),
+            },
+            Change {
+                path: "to".to_string(),
+                old_path: Some("from".to_string()),
+                staged: Some(ChangeKind::Renamed),
+                unstaged: None,
+            },
+        ];
+        assert_eq!(
+            vec!["?? new", "MM both", "R  from -> to"],
+            changes.iter().map(|c| c.short()).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            "2 staged, 1 unstaged, 1 untracked",
+            ChangeSummary::of(&changes).to_string()
+        );
+    }
+
+    #[test]
+    fn mirror_of_another_repo() -> Result<(), anyhow::Error> {
+        let dir = tempfile::tempdir()?;
+        let url = |name: &str| -> Result<String, anyhow::Error> {
+            git2::Repository::init_bare(dir.path().join(name))?;
+            Ok(format!("file://{}", dir.path().join(name).display()))
+        };
+        let (x_y, x_dash_y) = (url("x_y")?, url("x-y")?);
+        let mirror = dir.path().join("mirror.git");
+
+        super::clone_or_fetch(&x_y, &mirror, |_| ())?;
+        super::clone_or_fetch(&x_y, &mirror, |_| ())?;
+        assert!(super::clone_or_fetch(&x_dash_y, &mirror, |_| ()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn gone_upstream() -> Result<(), anyhow::Error> {
+        let dir = tempfile::tempdir()?;
+        let repo = git2::Repository::init(dir.path())?;
+        let sig = git2::Signature::now("a", "a@example.com")?;
+        let tree = repo.find_tree(repo.index()?.write_tree()?)?;
+        let tip = repo.commit(None, &sig, &sig, "a", &tree, &[])?;
+        let tip = repo.find_commit(tip)?;
+        repo.reference("refs/remotes/origin/kept", tip.id(), false, "test")?;
+        let mut config = repo.config()?;
+        config.set_str("remote.origin.url", "https://example.com/a")?;
+        config.set_str("remote.origin.fetch", "+refs/heads/*:refs/remotes/origin/*")?;
+        for name in ["kept", "deleted", "local"] {
+            repo.branch(name, &tip, false)?;
+            if name != "local" {
+                config.set_str(&format!("branch.{}.remote", name), "origin")?;
+                config.set_str(
+                    &format!("branch.{}.merge", name),
+                    &format!("refs/heads/{}", name),
+                )?;
+            }
+        }
+
+        let mut states = super::branch_states(&repo, "origin/kept")?
+            .iter()
+            .map(|state| state.to_string())
+            .collect::<Vec<_>>();
+        states.sort();
+        assert_eq!(
+            vec![
+                "deleted (upstream gone, merged)",
+                "kept",
+                "local (no upstream, merged)"
+            ],
+            states
+        );
+        Ok(())
+    }
+
     #[test]
     fn revwalk_direction() -> Result<(), anyhow::Error> {
         let repo = git2::Repository::open(".")?;
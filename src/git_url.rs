@@ -11,13 +11,15 @@ pub enum GitUrl {
     Ssh(String),
 }
 
+#[derive(Clone, Debug)]
 pub enum Provider {
     GithubCom { org: String, repo: String },
 }
 
 lazy_static! {
     static ref GITHUB_SSH: regex::Regex =
-        regex::Regex::new(r"git@[^:/]*github.com:/?([^/]+)/([^/]+)").expect("static regex");
+        regex::Regex::new(r"^git@(?:[^:/]*\.)?github\.com:/?([^/]+)/([^/]+)")
+            .expect("static regex");
 }
 
 impl FromStr for GitUrl {
@@ -45,26 +47,74 @@ impl GitUrl {
         }
     }
 
+    /// Whether `other` names the same repo, ignoring the protocol, user, and `.git` suffix,
+    /// so `git@github.com:a/b.git` matches `https://github.com/a/b`.
+    pub fn same_repo(&self, other: &str) -> bool {
+        match GitUrl::from_str(other) {
+            Ok(other) => self.normalized() == other.normalized(),
+            Err(_) => false,
+        }
+    }
+
+    /// The host and path without the repo name, e.g. `github.com/fauxfaux`, as a guess at
+    /// which repos belong together.
+    pub fn namespace(&self) -> String {
+        let normalized = self.normalized();
+        match normalized.rfind('/') {
+            Some(pos) => normalized[..pos].to_string(),
+            None => normalized,
+        }
+    }
+
+    /// `host/path`, lower-cased, without a user, port, or `.git`.
+    pub fn normalized(&self) -> String {
+        let (host, path) = match self {
+            GitUrl::Real(url) => (url.host_str().unwrap_or(""), url.path()),
+            GitUrl::Ssh(url) => {
+                let host = match url.find(':') {
+                    Some(pos) => &url[..pos],
+                    None => "",
+                };
+                let host = host.rsplit('@').next().unwrap_or(host);
+                (host, strip_to_colon(url).trim_start_matches(':'))
+            }
+        };
+        let path = path.trim_matches('/');
+        format!("{}/{}", host, strip_git(path)).to_ascii_lowercase()
+    }
+
+    /// The user in `https://user@host/` or `user@host:path`, if any.
+    pub fn username(&self) -> Option<&str> {
+        match self {
+            GitUrl::Real(url) => Some(url.username()).filter(|user| !user.is_empty()),
+            GitUrl::Ssh(url) => url
+                .split_once('@')
+                .map(|(user, _)| user)
+                .filter(|user| !user.is_empty() && !user.contains(':')),
+        }
+    }
+
     pub fn local_dir(&self) -> Result<&str, Error> {
         let base_name = match self {
             GitUrl::Real(url) => url
                 .path_segments()
                 .ok_or_else(|| anyhow!("no path in {:?}", url))?
-                .last()
+                .next_back()
                 .ok_or_else(|| anyhow!("empty path in {:?}", url))?,
-            GitUrl::Ssh(url) => strip_to_colon(&url)
+            GitUrl::Ssh(url) => strip_to_colon(url)
                 .split('/')
-                .last()
+                .next_back()
                 .ok_or_else(|| anyhow!("empty path in {:?}", url))?,
         };
 
-        Ok(strip_git(&base_name))
+        Ok(strip_git(base_name))
     }
 
     pub fn provider(&self) -> Option<Provider> {
         Some(match self {
             GitUrl::Real(url) => {
-                if !url.host_str()?.ends_with("github.com") {
+                let host = url.host_str()?;
+                if host != "github.com" && !host.ends_with(".github.com") {
                     return None;
                 }
                 let mut segments = url.path_segments()?;
@@ -93,7 +143,18 @@ impl Provider {
                 repo = repo,
                 branch = branch.unwrap_or("HEAD"),
                 path = path,
-                line = line.map(|n| format!("#L{}", n)).unwrap_or_else(String::new)
+                line = line.map(|n| format!("#L{}", n)).unwrap_or_default()
+            ),
+        }
+    }
+
+    pub fn html_commit(&self, commit: &str) -> String {
+        match self {
+            Provider::GithubCom { org, repo } => format!(
+                "https://github.com/{org}/{repo}/commit/{commit}",
+                org = org,
+                repo = repo,
+                commit = commit,
             ),
         }
     }
@@ -108,11 +169,7 @@ fn strip_to_colon(ssh: &str) -> &str {
 }
 
 fn strip_git(base_name: &str) -> &str {
-    if base_name.ends_with(".git") {
-        &base_name[..base_name.len() - 4]
-    } else {
-        base_name
-    }
+    base_name.strip_suffix(".git").unwrap_or(base_name)
 }
 
 #[cfg(test)]
@@ -148,6 +205,35 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn same_repo() -> Result<(), Error> {
+        let url = GitUrl::from_str("git@github.com:FauxFaux/gitgeoff.git")?;
+        assert!(url.same_repo("https://github.com/FauxFaux/gitgeoff"));
+        assert!(url.same_repo("ssh://git@github.com/FauxFaux/gitgeoff.git"));
+        assert!(url.same_repo("git@github.com:/FauxFaux/gitgeoff"));
+        assert!(!url.same_repo("git@github.com:someone/gitgeoff"));
+        assert!(!url.same_repo("https://gitlab.com/FauxFaux/gitgeoff"));
+        assert_eq!("github.com/fauxfaux", url.namespace());
+        Ok(())
+    }
+
+    #[test]
+    fn get_username() -> Result<(), Error> {
+        assert_eq!(
+            Some("git"),
+            GitUrl::from_str("git@github.com:FauxFaux/gitgeoff")?.username()
+        );
+        assert_eq!(
+            Some("faux"),
+            GitUrl::from_str("ssh://faux@example.com/gitgeoff")?.username()
+        );
+        assert_eq!(
+            None,
+            GitUrl::from_str("https://github.com/FauxFaux/gitgeoff")?.username()
+        );
+        Ok(())
+    }
+
     #[test]
     fn get_provider() -> Result<(), Error> {
         assert_eq!(
@@ -157,6 +243,28 @@ mod tests {
                 .unwrap()
                 .html_browse_path(None, "foo/bar.txt", Some(7))
         );
+        assert_eq!(
+            "https://github.com/FauxFaux/gitgeoff/commit/abc123",
+            GitUrl::from_str("https://github.com/FauxFaux/gitgeoff")?
+                .provider()
+                .unwrap()
+                .html_commit("abc123")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn only_github_is_github() -> Result<(), Error> {
+        assert!(GitUrl::from_str("https://evilgithub.com/a/b")?
+            .provider()
+            .is_none());
+        assert!(GitUrl::from_str("https://github.com.attacker.net/a/b")?
+            .provider()
+            .is_none());
+        assert!(GitUrl::from_str("git@evilgithub.com:a/b")?
+            .provider()
+            .is_none());
+        assert!(GitUrl::from_str("git@github.com:a/b")?.provider().is_some());
         Ok(())
     }
 
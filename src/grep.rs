@@ -1,99 +1,1191 @@
+use std::borrow::Cow;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::io::Write;
 use std::path::Path;
+use std::path::PathBuf;
 
 use anyhow::anyhow;
+use anyhow::bail;
 use anyhow::Context;
 use anyhow::Error;
+use grep_matcher::Matcher;
 use grep_regex::RegexMatcher;
-use grep_searcher::sinks::Lossy;
+use grep_regex::RegexMatcherBuilder;
+use grep_searcher::BinaryDetection;
 use grep_searcher::Searcher;
+use grep_searcher::SearcherBuilder;
+use grep_searcher::Sink;
+use grep_searcher::SinkContext;
+use grep_searcher::SinkMatch;
+use log::error;
+use log::warn;
 use rayon::iter::IntoParallelIterator;
 use rayon::iter::ParallelIterator;
+use regex_syntax::ast::Ast;
+use regex_syntax::ast::ClassSetItem;
 
+use super::attributes::Attributes;
 use super::config;
+use super::git;
+use super::index::Candidates;
+use super::index::Index;
+use super::index::Query;
+use super::Cache;
 use crate::git_url::Provider;
+use crate::tags::TagFilter;
 use config::Spec;
 
-pub fn grep(pattern: &str, globs: &[&String]) -> Result<(), Error> {
-    let globs = {
-        let mut builder = globset::GlobSetBuilder::new();
-        for &glob in globs {
-            builder.add(globset::Glob::new(glob)?);
+/// Which version of each repo to search.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Target {
+    /// the default: each repo's upstream, e.g. `origin/REMOTE_HEAD`
+    Upstream,
+    /// a commit
+    Rev(String),
+    /// what's staged, i.e. `git grep --cached`
+    Index,
+    /// the files on disk, except those ignored by `.gitignore`
+    Worktree,
+}
+
+/// Somewhere to read a file's content from.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Source {
+    Blob(git2::Oid),
+    Disk(PathBuf),
+}
+
+struct File {
+    path: String,
+    source: Source,
+}
+
+/// How to show what was found.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Output {
+    /// every matching line, and any context
+    #[default]
+    Lines,
+    /// only the names of files with a match, `-l`
+    Files,
+    /// the number of matching lines in each file, `-c`
+    Count,
+    /// the number of matching lines and files in each repo
+    Summary,
+    /// ripgrep's `--json` messages, plus the repo and a link
+    Json,
+}
+
+/// How to match and what to show, named after ripgrep's flags.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    pub ignore_case: bool,
+    pub smart_case: bool,
+    pub fixed_strings: bool,
+    pub word_regexp: bool,
+    pub invert_match: bool,
+    pub before_context: usize,
+    pub after_context: usize,
+    /// per file
+    pub max_count: Option<u64>,
+    pub multiline: bool,
+    /// search files with NUL bytes in, as if they were text, `-a`
+    pub text: bool,
+    /// in bytes
+    pub max_filesize: Option<u64>,
+    pub output: Output,
+}
+
+impl Options {
+    pub fn matcher(&self, pattern: &str) -> Result<RegexMatcher, Error> {
+        let mut builder = RegexMatcherBuilder::new();
+        builder
+            .case_insensitive(self.ignore_case)
+            .case_smart(self.smart_case)
+            .fixed_strings(self.fixed_strings)
+            .word(self.word_regexp)
+            .multi_line(true);
+        if !self.multiline {
+            builder.line_terminator(Some(b'\n'));
         }
+        Ok(builder.build(pattern)?)
+    }
 
-        builder.build()?
-    };
+    fn searcher(&self) -> Searcher {
+        // context is only ever shown alongside the lines themselves
+        let with_context = matches!(self.output, Output::Lines | Output::Json);
+        SearcherBuilder::new()
+            .invert_match(self.invert_match)
+            .before_context(if with_context { self.before_context } else { 0 })
+            .after_context(if with_context { self.after_context } else { 0 })
+            .multi_line(self.multiline)
+            .binary_detection(if self.text {
+                BinaryDetection::none()
+            } else {
+                BinaryDetection::quit(b'\0')
+            })
+            .line_number(true)
+            .build()
+    }
 
-    config::load()?
-        .into_par_iter()
-        .try_for_each(|s: Spec| -> Result<(), Error> {
-            let dest = s.url.local_dir()?;
-            if !Path::new(dest).exists() {
-                return Ok(());
+    fn max_count(&self) -> Option<u64> {
+        match self.output {
+            Output::Files => Some(1),
+            _ => self.max_count,
+        }
+    }
+
+    /// The same matching, for searching outside of the searcher, e.g. `grep --log`.
+    pub fn bytes_regex(&self, pattern: &str) -> Result<regex::bytes::Regex, Error> {
+        let pattern = self.regex(pattern);
+        let smart = self.smart_case && smart_insensitive(&pattern)?;
+        Ok(regex::bytes::RegexBuilder::new(&pattern)
+            .case_insensitive(self.ignore_case || smart)
+            .multi_line(true)
+            .build()?)
+    }
+
+    /// What the index can rule out, if anything; an inverted match could be anywhere.
+    fn query(&self, pattern: &str) -> Result<Option<Query>, Error> {
+        if self.invert_match {
+            return Ok(None);
+        }
+        // smart case might be case sensitive, but assuming it isn't only costs speed
+        let query = Query::new(&self.regex(pattern), self.ignore_case || self.smart_case)?;
+        Ok(Some(query).filter(|query| Query::All != *query))
+    }
+
+    fn regex(&self, pattern: &str) -> String {
+        let pattern = if self.fixed_strings {
+            regex::escape(pattern)
+        } else {
+            pattern.to_string()
+        };
+        if self.word_regexp {
+            format!(r"\b(?:{})\b", pattern)
+        } else {
+            pattern
+        }
+    }
+}
+
+/// Whether `--smart-case` makes `pattern` case insensitive: it has a literal, and none are upper
+/// case. This is how `grep_regex` decides, so `\W` or `\p{Lu}` don't count.
+fn smart_insensitive(pattern: &str) -> Result<bool, Error> {
+    #[derive(Default)]
+    struct Literals {
+        any: bool,
+        upper: bool,
+    }
+
+    impl Literals {
+        fn saw(&mut self, c: char) {
+            self.any = true;
+            self.upper |= c.is_uppercase();
+        }
+    }
+
+    impl regex_syntax::ast::Visitor for Literals {
+        type Output = Literals;
+        type Err = std::convert::Infallible;
+
+        fn finish(self) -> Result<Literals, Self::Err> {
+            Ok(self)
+        }
+
+        fn visit_pre(&mut self, ast: &Ast) -> Result<(), Self::Err> {
+            if let Ast::Literal(literal) = ast {
+                self.saw(literal.c);
             }
-            let repo = git2::Repository::open(dest)?;
-            grep_in(pattern, dest, s.url.provider().as_ref(), &globs, &repo)?;
             Ok(())
-        })?;
-    Ok(())
+        }
+
+        fn visit_class_set_item_pre(&mut self, item: &ClassSetItem) -> Result<(), Self::Err> {
+            match item {
+                ClassSetItem::Literal(literal) => self.saw(literal.c),
+                ClassSetItem::Range(range) => {
+                    self.saw(range.start.c);
+                    self.saw(range.end.c);
+                }
+                _ => (),
+            }
+            Ok(())
+        }
+    }
+
+    let ast = regex_syntax::ast::parse::Parser::new().parse(pattern)?;
+    let literals = regex_syntax::ast::visit(&ast, Literals::default())?;
+    Ok(literals.any && !literals.upper)
+}
+
+/// Something which couldn't be searched; everything else still is.
+struct Failure {
+    repo: String,
+    /// if it was only one file
+    path: Option<String>,
+    error: Error,
+}
+
+enum Listed {
+    /// not cloned
+    Absent,
+    /// this commit isn't in this repo
+    NoRev(String),
+    Files(Listing),
+}
+
+/// What to search in one repo.
+struct Listing {
+    prefix: String,
+    /// where to link to, if a commit on a known provider is being searched
+    link: Option<(Provider, String)>,
+    files: Vec<File>,
+}
+
+/// One distinct file to search, and everywhere it appears, as `(repo, file)` indexes.
+struct Work<'l> {
+    repo: usize,
+    source: &'l Source,
+    places: Vec<(usize, usize)>,
+}
+
+/// Everything found in one repo, held so repos can be printed in `.gitgeoff` order.
+struct Found {
+    prefix: String,
+    /// where to link to, if a commit on a known provider was searched
+    link: Option<(Provider, String)>,
+    files: Vec<FileMatches>,
 }
 
-fn grep_in(
+struct FileMatches {
+    path: String,
+    events: Vec<Event>,
+}
+
+#[derive(Clone)]
+enum Event {
+    Match(Lines),
+    Context(Lines),
+    /// a gap between runs of lines, `--`
+    Break,
+}
+
+#[derive(Clone)]
+struct Lines {
+    line_number: u64,
+    absolute_offset: u64,
+    bytes: Vec<u8>,
+    /// byte ranges in `bytes`
+    submatches: Vec<(usize, usize)>,
+}
+
+impl Found {
+    fn path(&self, path: &str, line: Option<u64>) -> String {
+        match &self.link {
+            Some((provider, commit)) => {
+                href(path, &provider.html_browse_path(Some(commit), path, line))
+            }
+            None => path.to_string(),
+        }
+    }
+}
+
+impl FileMatches {
+    fn matched_lines(&self) -> u64 {
+        self.matches().map(|lines| lines.count() as u64).sum()
+    }
+
+    fn submatches(&self) -> u64 {
+        self.matches()
+            .map(|lines| lines.submatches.len() as u64)
+            .sum()
+    }
+
+    fn matches(&self) -> impl Iterator<Item = &Lines> {
+        self.events.iter().filter_map(|event| match event {
+            Event::Match(lines) => Some(lines),
+            _ => None,
+        })
+    }
+}
+
+impl Lines {
+    fn new(line_number: Option<u64>, absolute_offset: u64, bytes: &[u8]) -> Lines {
+        Lines {
+            line_number: line_number.unwrap_or(0),
+            absolute_offset,
+            bytes: bytes.to_vec(),
+            submatches: Vec::new(),
+        }
+    }
+
+    fn count(&self) -> usize {
+        self.bytes.split_inclusive(|&b| b == b'\n').count()
+    }
+}
+
+/// Keeps everything the searcher finds in a file, up to `max_count` matches.
+struct Collector<'m> {
+    matcher: &'m RegexMatcher,
+    invert_match: bool,
+    max_count: Option<u64>,
+    matches: u64,
+    /// found a NUL, so the file is skipped, like ripgrep does
+    binary: bool,
+    events: Vec<Event>,
+}
+
+impl Sink for Collector<'_> {
+    type Error = io::Error;
+
+    fn matched(&mut self, _: &Searcher, mat: &SinkMatch) -> Result<bool, io::Error> {
+        // `-m 0` still gets here once
+        if self.max_count == Some(0) {
+            return Ok(false);
+        }
+        let mut lines = Lines::new(mat.line_number(), mat.absolute_byte_offset(), mat.bytes());
+        if !self.invert_match {
+            self.matcher
+                .find_iter(mat.bytes(), |m| {
+                    lines.submatches.push((m.start(), m.end()));
+                    true
+                })
+                .map_err(io::Error::other)?;
+        }
+        self.events.push(Event::Match(lines));
+        self.matches += 1;
+        Ok(self.max_count.is_none_or(|max| self.matches < max))
+    }
+
+    fn context(&mut self, _: &Searcher, context: &SinkContext) -> Result<bool, io::Error> {
+        self.events.push(Event::Context(Lines::new(
+            context.line_number(),
+            context.absolute_byte_offset(),
+            context.bytes(),
+        )));
+        Ok(true)
+    }
+
+    fn context_break(&mut self, _: &Searcher) -> Result<bool, io::Error> {
+        self.events.push(Event::Break);
+        Ok(true)
+    }
+
+    fn binary_data(&mut self, _: &Searcher, _: u64) -> Result<bool, io::Error> {
+        self.binary = true;
+        Ok(false)
+    }
+}
+
+pub fn grep(
+    cache: &Cache,
+    filter: &TagFilter,
+    target: &Target,
     pattern: &str,
+    globs: &[&String],
+    options: &Options,
+) -> Result<(), Error> {
+    let globs = build_globs(globs)?;
+    let matcher = options.matcher(pattern)?;
+    let query = options.query(pattern)?;
+
+    let listed = config::load_matching(filter)?
+        .into_par_iter()
+        .map(|s: Spec| {
+            let label = s.local_dir().unwrap_or(s.url.as_str()).to_string();
+            (
+                label,
+                list_repo(cache, &s, query.as_ref(), target, &globs, options.text),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let mut repos = 0;
+    let mut skipped = Vec::new();
+    let mut failures = Vec::new();
+    let mut listings = Vec::new();
+    for (label, listed) in listed {
+        match listed {
+            Ok(Listed::Absent) => continue,
+            Ok(Listed::Files(listing)) => listings.push(listing),
+            Ok(Listed::NoRev(rev)) => skipped.push((label, rev)),
+            Err(error) => failures.push(Failure {
+                repo: label,
+                path: None,
+                error,
+            }),
+        }
+        repos += 1;
+    }
+
+    // search every distinct blob once, wherever it appears, with the work for
+    // every repo in one pool, so a huge repo doesn't leave the other threads idle
+    let mut work: Vec<Work> = Vec::new();
+    let mut seen: HashMap<&Source, usize> = HashMap::new();
+    for (repo, listing) in listings.iter().enumerate() {
+        for (file, entry) in listing.files.iter().enumerate() {
+            match seen.entry(&entry.source) {
+                Entry::Occupied(existing) => work[*existing.get()].places.push((repo, file)),
+                Entry::Vacant(vacant) => {
+                    vacant.insert(work.len());
+                    work.push(Work {
+                        repo,
+                        source: &entry.source,
+                        places: vec![(repo, file)],
+                    });
+                }
+            }
+        }
+    }
+
+    let results = work
+        .into_par_iter()
+        .map_init(HashMap::new, |open, work| {
+            let events = search(&matcher, options, &listings[work.repo], work.source, open);
+            (work.places, events)
+        })
+        .collect::<Vec<_>>();
+
+    let mut found = listings
+        .iter()
+        .map(|listing| Found {
+            prefix: listing.prefix.clone(),
+            link: listing.link.clone(),
+            files: Vec::new(),
+        })
+        .collect::<Vec<_>>();
+    let mut order = vec![Vec::new(); found.len()];
+    for (places, events) in results {
+        let events = match events {
+            Ok(Some(events)) => events,
+            Ok(None) => continue,
+            Err(error) => {
+                // it's the same blob everywhere, so the first place will do
+                let (repo, file) = places[0];
+                failures.push(Failure {
+                    repo: listings[repo].prefix.clone(),
+                    path: Some(listings[repo].files[file].path.clone()),
+                    error,
+                });
+                continue;
+            }
+        };
+        for (repo, file) in places {
+            order[repo].push(file);
+            found[repo].files.push(FileMatches {
+                path: listings[repo].files[file].path.clone(),
+                events: events.clone(),
+            });
+        }
+    }
+
+    for (found, order) in found.iter_mut().zip(order) {
+        let mut files = order
+            .into_iter()
+            .zip(found.files.drain(..))
+            .collect::<Vec<_>>();
+        files.sort_by_key(|(file, _)| *file);
+        found.files = files.into_iter().map(|(_, file)| file).collect();
+    }
+
+    match options.output {
+        Output::Lines => print_lines(&found),
+        Output::Files => print_files(&found),
+        Output::Count => print_counts(&found),
+        Output::Summary => print_summary(&found),
+        Output::Json => print_json(&found)?,
+    }
+
+    for (repo, rev) in skipped {
+        warn!("{}: no {:?}, skipped", repo, rev);
+    }
+    report(&failures, repos)
+}
+
+/// Log every failure, and fail if there were any.
+fn report(failures: &[Failure], repos: usize) -> Result<(), Error> {
+    if failures.is_empty() {
+        return Ok(());
+    }
+    for failure in failures {
+        match &failure.path {
+            Some(path) => error!("{}/{}: {:#}", failure.repo, path, failure.error),
+            None => error!("{}: {:#}", failure.repo, failure.error),
+        }
+    }
+    let failed = failures
+        .iter()
+        .map(|failure| &failure.repo)
+        .collect::<HashSet<_>>()
+        .len();
+    bail!("{} of {} repos had errors", failed, repos)
+}
+
+/// A size in bytes, with an optional `K`, `M` or `G` suffix, like ripgrep's `--max-filesize`.
+pub fn parse_size(size: &str) -> Result<u64, Error> {
+    let (number, shift) = match size.as_bytes().last() {
+        Some(b'K') => (&size[..size.len() - 1], 10),
+        Some(b'M') => (&size[..size.len() - 1], 20),
+        Some(b'G') => (&size[..size.len() - 1], 30),
+        _ => (size, 0),
+    };
+    let number: u64 = number
+        .parse()
+        .with_context(|| anyhow!("parsing size {:?}", size))?;
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| anyhow!("size too large: {:?}", size))
+}
+
+pub fn build_globs(globs: &[&String]) -> Result<globset::GlobSet, Error> {
+    let mut builder = globset::GlobSetBuilder::new();
+    for &glob in globs {
+        builder.add(globset::Glob::new(glob)?);
+    }
+
+    Ok(builder.build()?)
+}
+
+fn list_repo(
+    cache: &Cache,
+    spec: &Spec,
+    query: Option<&Query>,
+    target: &Target,
+    globs: &globset::GlobSet,
+    text: bool,
+) -> Result<Listed, Error> {
+    let dest = spec.local_dir()?;
+    if !Path::new(dest).exists() {
+        return Ok(Listed::Absent);
+    }
+    let repo = git2::Repository::open(dest)?;
+    let target = match target {
+        Target::Upstream => &Target::Rev(spec.upstream()),
+        target => target,
+    };
+    let candidates = match query {
+        Some(query) => Index::load(&cache.index(&spec.url)?)?.map(|index| index.candidates(query)),
+        None => None,
+    };
+    list_in(
+        dest,
+        spec.url.provider(),
+        target,
+        globs,
+        candidates.as_ref(),
+        text,
+        &repo,
+    )
+}
+
+/// The files to search in one repo.
+fn list_in(
     prefix: &str,
-    provider: Option<&Provider>,
+    provider: Option<Provider>,
+    target: &Target,
     globs: &globset::GlobSet,
+    candidates: Option<&Candidates>,
+    text: bool,
     repo: &git2::Repository,
-) -> Result<(), Error> {
-    let matcher = RegexMatcher::new(pattern)?;
-    let tree_obj = repo
-        .revparse_single("origin/REMOTE_HEAD")
-        .with_context(|| anyhow!("looking in {:?}", prefix))?
-        .peel_to_tree()?;
-    let mut err = Vec::new();
-
-    tree_obj.walk(git2::TreeWalkMode::PostOrder, |dir, entry| {
-        match entry.kind() {
-            Some(git2::ObjectType::Blob) => (),
-            _ => return git2::TreeWalkResult::Ok,
+) -> Result<Listed, Error> {
+    // only committed files can be linked to
+    let (mut files, commit) = match target {
+        Target::Rev(rev) => {
+            let commit = match git::if_found(repo.revparse_single(rev))? {
+                Some(object) => object.peel_to_commit()?,
+                None => return Ok(Listed::NoRev(rev.to_string())),
+            };
+            let files =
+                tree_files(&commit.tree()?).with_context(|| anyhow!("looking in {:?}", prefix))?;
+            (files, Some(commit.id().to_string()))
+        }
+        Target::Index => (index_files(repo)?, None),
+        Target::Worktree => (worktree_files(repo)?, None),
+        Target::Upstream => unreachable!("list_repo resolves the upstream"),
+    };
+
+    let attributes = attributes(repo, &files)
+        .with_context(|| anyhow!("reading .gitattributes in {:?}", prefix))?;
+    files.retain(|file| {
+        let may_match = match (&file.source, candidates) {
+            (Source::Blob(oid), Some(candidates)) => candidates.may_match(*oid),
+            _ => true,
         };
-        let object = entry.to_object(&repo).expect("TODO: can't load blob");
+        may_match
+            && (globs.is_empty() || globs.is_match(&file.path))
+            && (text || !attributes.skip(&file.path))
+    });
 
-        let content = object.as_blob().expect("type checked above").content();
+    Ok(Listed::Files(Listing {
+        prefix: prefix.to_string(),
+        link: provider.zip(commit),
+        files,
+    }))
+}
 
-        let path = format!("{}{}", dir, entry.name().expect("blobs have names"));
+/// The files `grep --worktree` would search, relative to the repo, and where they are on disk.
+pub fn worktree_paths(
+    prefix: &str,
+    repo: &git2::Repository,
+    globs: &globset::GlobSet,
+    text: bool,
+) -> Result<Vec<(String, PathBuf)>, Error> {
+    let files = match list_in(prefix, None, &Target::Worktree, globs, None, text, repo)? {
+        Listed::Files(listing) => listing.files,
+        Listed::Absent | Listed::NoRev(_) => Vec::new(),
+    };
+    Ok(files
+        .into_iter()
+        .filter_map(|file| match file.source {
+            Source::Disk(on_disk) => Some((file.path, on_disk)),
+            Source::Blob(_) => None,
+        })
+        .collect())
+}
 
-        if !globs.is_empty() && !globs.is_match(&path) {
-            return git2::TreeWalkResult::Ok;
+/// Every `.gitattributes` in `files`, shallowest first.
+fn attributes(repo: &git2::Repository, files: &[File]) -> Result<Attributes, Error> {
+    let mut found = files
+        .iter()
+        .filter(|file| file.path.rsplit('/').next() == Some(".gitattributes"))
+        .collect::<Vec<_>>();
+    found.sort_by_key(|file| file.path.matches('/').count());
+
+    let mut attributes = Attributes::default();
+    for file in found {
+        match &file.source {
+            Source::Blob(oid) => attributes.add(&file.path, repo.find_blob(*oid)?.content())?,
+            Source::Disk(path) => attributes.add(&file.path, &fs::read(path)?)?,
         }
+    }
+    Ok(attributes)
+}
 
-        let status = Searcher::new().search_slice(
-            &matcher,
-            content,
-            Lossy(|lnum, line| {
-                let path = provider
-                    .map(|p| href(&path, &p.html_browse_path(None, &path, Some(lnum))))
-                    .unwrap_or_else(|| path.to_string());
-                println!("{}/{} {}: {}", prefix, path, lnum, line.trim_end());
-                Ok(true)
-            }),
+/// Everything the searcher found in one file, if there was a match.
+fn search(
+    matcher: &RegexMatcher,
+    options: &Options,
+    listing: &Listing,
+    source: &Source,
+    open: &mut HashMap<String, git2::Repository>,
+) -> Result<Option<Vec<Event>>, Error> {
+    let too_big = |size: u64| options.max_filesize.is_some_and(|max| size > max);
+    let blob;
+    let on_disk;
+    let content = match source {
+        Source::Blob(oid) => {
+            let repo = match open.entry(listing.prefix.clone()) {
+                Entry::Occupied(repo) => repo.into_mut(),
+                Entry::Vacant(vacant) => vacant.insert(git2::Repository::open(&listing.prefix)?),
+            };
+            // the header is much cheaper than the whole blob
+            let (size, _) = repo.odb()?.read_header(*oid)?;
+            if too_big(size as u64) {
+                return Ok(None);
+            }
+            blob = repo.find_blob(*oid)?;
+            blob.content()
+        }
+        Source::Disk(path) => {
+            if too_big(fs::metadata(path)?.len()) {
+                return Ok(None);
+            }
+            on_disk = fs::read(path)?;
+            &on_disk[..]
+        }
+    };
+
+    let mut collector = Collector {
+        matcher,
+        invert_match: options.invert_match,
+        max_count: options.max_count(),
+        matches: 0,
+        binary: false,
+        events: Vec::new(),
+    };
+    options
+        .searcher()
+        .search_slice(matcher, content, &mut collector)?;
+
+    Ok(if collector.matches > 0 && !collector.binary {
+        Some(collector.events)
+    } else {
+        None
+    })
+}
+
+fn print_lines(found: &[Found]) {
+    for repo in found {
+        for file in &repo.files {
+            for event in &file.events {
+                let (lines, separator) = match event {
+                    Event::Match(lines) => (lines, ':'),
+                    Event::Context(lines) => (lines, '-'),
+                    Event::Break => {
+                        println!("--");
+                        continue;
+                    }
+                };
+                for (offset, line) in lines.bytes.split_inclusive(|&b| b == b'\n').enumerate() {
+                    let lnum = lines.line_number + offset as u64;
+                    println!(
+                        "{}/{} {}{} {}",
+                        repo.prefix,
+                        repo.path(&file.path, Some(lnum)),
+                        lnum,
+                        separator,
+                        String::from_utf8_lossy(line).trim_end()
+                    );
+                }
+            }
+        }
+    }
+}
+
+fn print_files(found: &[Found]) {
+    for repo in found {
+        for file in &repo.files {
+            println!("{}/{}", repo.prefix, repo.path(&file.path, None));
+        }
+    }
+}
+
+fn print_counts(found: &[Found]) {
+    for repo in found {
+        for file in &repo.files {
+            println!(
+                "{}/{}: {}",
+                repo.prefix,
+                repo.path(&file.path, None),
+                file.matched_lines()
+            );
+        }
+    }
+}
+
+fn print_summary(found: &[Found]) {
+    for repo in found {
+        if repo.files.is_empty() {
+            continue;
+        }
+        let lines: u64 = repo.files.iter().map(|file| file.matched_lines()).sum();
+        println!(
+            "{}: {} matching lines in {} files",
+            repo.prefix,
+            lines,
+            repo.files.len()
         );
+    }
+}
 
-        match status {
-            Ok(()) => (),
-            Err(e) => err.push(e),
+/// One line of ripgrep's `--json` output, with `repo` and `url` added.
+#[derive(serde_derive::Serialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+enum Message<'f> {
+    Begin {
+        repo: &'f str,
+        path: Text<'f>,
+    },
+    Match(JsonLines<'f>),
+    Context(JsonLines<'f>),
+    End {
+        repo: &'f str,
+        path: Text<'f>,
+        stats: Stats,
+    },
+    Summary {
+        stats: Stats,
+    },
+}
+
+#[derive(serde_derive::Serialize)]
+struct Text<'f> {
+    text: Cow<'f, str>,
+}
+
+#[derive(serde_derive::Serialize)]
+struct JsonLines<'f> {
+    repo: &'f str,
+    path: Text<'f>,
+    url: Option<String>,
+    lines: Text<'f>,
+    line_number: u64,
+    absolute_offset: u64,
+    submatches: Vec<Submatch<'f>>,
+}
+
+#[derive(serde_derive::Serialize)]
+struct Submatch<'f> {
+    #[serde(rename = "match")]
+    matched: Text<'f>,
+    start: usize,
+    end: usize,
+}
+
+#[derive(Default, serde_derive::Serialize)]
+struct Stats {
+    searches_with_match: u64,
+    matched_lines: u64,
+    matches: u64,
+}
+
+impl Found {
+    fn json_lines<'f>(
+        &'f self,
+        path: &'f str,
+        file: &FileMatches,
+        lines: &'f Lines,
+    ) -> JsonLines<'f> {
+        JsonLines {
+            repo: &self.prefix,
+            path: Text::new(path.as_bytes()),
+            url: self.link.as_ref().map(|(provider, commit)| {
+                provider.html_browse_path(Some(commit), &file.path, Some(lines.line_number))
+            }),
+            lines: Text::new(&lines.bytes),
+            line_number: lines.line_number,
+            absolute_offset: lines.absolute_offset,
+            submatches: lines
+                .submatches
+                .iter()
+                .map(|&(start, end)| Submatch {
+                    matched: Text::new(&lines.bytes[start..end]),
+                    start,
+                    end,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl<'f> Text<'f> {
+    fn new(bytes: &'f [u8]) -> Text<'f> {
+        Text {
+            text: String::from_utf8_lossy(bytes),
+        }
+    }
+}
+
+fn print_json(found: &[Found]) -> Result<(), Error> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let mut emit = |message: &Message| -> Result<(), Error> {
+        serde_json::to_writer(&mut stdout, message)?;
+        writeln!(stdout)?;
+        Ok(())
+    };
+
+    let mut total = Stats::default();
+    for repo in found {
+        for file in &repo.files {
+            let path = format!("{}/{}", repo.prefix, file.path);
+            emit(&Message::Begin {
+                repo: &repo.prefix,
+                path: Text::new(path.as_bytes()),
+            })?;
+            for event in &file.events {
+                match event {
+                    Event::Match(lines) => {
+                        emit(&Message::Match(repo.json_lines(&path, file, lines)))?
+                    }
+                    Event::Context(lines) => {
+                        emit(&Message::Context(repo.json_lines(&path, file, lines)))?
+                    }
+                    Event::Break => (),
+                }
+            }
+            let stats = Stats {
+                searches_with_match: 1,
+                matched_lines: file.matched_lines(),
+                matches: file.submatches(),
+            };
+            total.searches_with_match += stats.searches_with_match;
+            total.matched_lines += stats.matched_lines;
+            total.matches += stats.matches;
+            emit(&Message::End {
+                repo: &repo.prefix,
+                path: Text::new(path.as_bytes()),
+                stats,
+            })?;
         }
+    }
+    emit(&Message::Summary { stats: total })?;
+
+    Ok(())
+}
 
+fn tree_files(tree: &git2::Tree) -> Result<Vec<File>, Error> {
+    let mut files = Vec::new();
+    tree.walk(git2::TreeWalkMode::PreOrder, |dir, entry| {
+        if Some(git2::ObjectType::Blob) == entry.kind() {
+            files.push(File {
+                path: format!("{}{}", dir, String::from_utf8_lossy(entry.name_bytes())),
+                source: Source::Blob(entry.id()),
+            });
+        }
         git2::TreeWalkResult::Ok
     })?;
+    Ok(files)
+}
+
+/// The staged files, without submodules, or the sides of a conflict.
+fn index_files(repo: &git2::Repository) -> Result<Vec<File>, Error> {
+    Ok(repo
+        .index()?
+        .iter()
+        .filter(|entry| {
+            let stage = (entry.flags >> 12) & 0x3;
+            let gitlink = entry.mode & 0o170000 == 0o160000;
+            0 == stage && !gitlink
+        })
+        .map(|entry| File {
+            path: String::from_utf8_lossy(&entry.path).to_string(),
+            source: Source::Blob(entry.id),
+        })
+        .collect())
+}
 
-    // TODO: ..and the other errors, if any?
-    if let Some(e) = err.into_iter().next() {
-        Err(e)?;
+/// Everything on disk, except `.gitignore`d files, and other repos nested inside this one.
+fn worktree_files(repo: &git2::Repository) -> Result<Vec<File>, Error> {
+    let root = repo
+        .workdir()
+        .ok_or_else(|| anyhow!("no working tree in {:?}", repo.path()))?;
+
+    let mut files = Vec::new();
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(root.join(&dir))? {
+            let entry = entry?;
+            if entry.file_name() == ".git" {
+                continue;
+            }
+            let relative = dir.join(entry.file_name());
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                if entry.path().join(".git").exists()
+                    || repo.is_path_ignored(format!("{}/", relative.display()))?
+                {
+                    continue;
+                }
+                dirs.push(relative);
+            } else if file_type.is_file() && !repo.is_path_ignored(&relative)? {
+                files.push(File {
+                    path: relative.to_string_lossy().to_string(),
+                    source: Source::Disk(entry.path()),
+                });
+            }
+        }
     }
 
-    Ok(())
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
 }
 
-fn href(label: &str, url: &str) -> String {
+pub fn href(label: &str, url: &str) -> String {
     format!("\x1b]8;;{}\x1b\\{}\x1b]8;;\x1b\\", url, label)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use anyhow::Error;
+    use grep_matcher::Matcher;
+
+    use super::index_files;
+    use super::list_in;
+    use super::parse_size;
+    use super::search;
+    use super::Collector;
+    use super::Event;
+    use super::Index;
+    use super::Listed;
+    use super::Options;
+    use super::Target;
+
+    #[test]
+    fn index_without_submodules_or_conflicts() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let repo = git2::Repository::init(dir.path())?;
+        let mut index = repo.index()?;
+        let entry = |path: &str, mode: u32, stage: u16| git2::IndexEntry {
+            ctime: git2::IndexTime::new(0, 0),
+            mtime: git2::IndexTime::new(0, 0),
+            dev: 0,
+            ino: 0,
+            mode,
+            uid: 0,
+            gid: 0,
+            file_size: 0,
+            id: repo.blob(path.as_bytes()).expect("writing blob"),
+            flags: stage << 12 | path.len() as u16,
+            flags_extended: 0,
+            path: path.as_bytes().to_vec(),
+        };
+        index.add(&entry("file", 0o100644, 0))?;
+        index.add(&entry("sub", 0o160000, 0))?;
+        for stage in 1..=3 {
+            index.add(&entry("conflicted", 0o100644, stage))?;
+        }
+        index.write()?;
+
+        let files = index_files(&repo)?;
+        assert_eq!(
+            vec!["file"],
+            files.iter().map(|f| f.path.as_str()).collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn index_and_bom() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let repo = git2::Repository::init(dir.path())?;
+        let utf16 = [
+            &[0xff, 0xfe][..],
+            &"say hello\n"
+                .encode_utf16()
+                .flat_map(u16::to_le_bytes)
+                .collect::<Vec<u8>>(),
+        ]
+        .concat();
+        let mut tree = repo.treebuilder(None)?;
+        tree.insert("u16.txt", repo.blob(&utf16)?, 0o100644)?;
+        tree.insert("plain.txt", repo.blob(b"hello\n")?, 0o100644)?;
+        tree.insert("other.txt", repo.blob(b"goodbye\n")?, 0o100644)?;
+        let tree = repo.find_tree(tree.write()?)?;
+        let sig = git2::Signature::now("a", "a@example.com")?;
+        repo.commit(Some("HEAD"), &sig, &sig, "a", &tree, &[])?;
+
+        let options = Options::default();
+        let matcher = options.matcher("hello")?;
+        let query = options.query("hello")?.expect("indexable");
+        let (index, _) = Index::build(&repo, &tree, None)?;
+        let candidates = index.candidates(&query);
+
+        let prefix = dir.path().to_string_lossy().to_string();
+        let globs = globset::GlobSet::empty();
+        let target = Target::Rev("HEAD".to_string());
+        let found = |candidates| -> Result<Vec<String>, Error> {
+            let listing = match list_in(&prefix, None, &target, &globs, candidates, false, &repo)? {
+                Listed::Files(listing) => listing,
+                _ => panic!("HEAD exists"),
+            };
+            let mut open = HashMap::new();
+            let mut found = Vec::new();
+            for file in &listing.files {
+                if search(&matcher, &options, &listing, &file.source, &mut open)?.is_some() {
+                    found.push(file.path.clone());
+                }
+            }
+            found.sort();
+            Ok(found)
+        };
+        assert_eq!(vec!["plain.txt", "u16.txt"], found(None)?);
+        assert_eq!(found(None)?, found(Some(&candidates))?);
+        Ok(())
+    }
+
+    #[test]
+    fn smart_case() -> Result<(), Error> {
+        let options = Options {
+            smart_case: true,
+            ..Options::default()
+        };
+        for pattern in [
+            "foo",
+            r"\Wfoo",
+            r"\p{Lu}oo",
+            r"\Dfoo",
+            "Foo",
+            "[A-Z]oo",
+            r"\d",
+        ] {
+            for text in ["a FOO", "a foo", "Foo1"] {
+                assert_eq!(
+                    options.matcher(pattern)?.is_match(text.as_bytes())?,
+                    options.bytes_regex(pattern)?.is_match(text.as_bytes()),
+                    "{:?} in {:?}",
+                    pattern,
+                    text
+                );
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn sizes() -> Result<(), Error> {
+        assert_eq!(100, parse_size("100")?);
+        assert_eq!(2048, parse_size("2K")?);
+        assert_eq!(3 << 20, parse_size("3M")?);
+        assert!(parse_size("3T").is_err());
+        assert!(parse_size("M").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn collect() -> Result<(), Error> {
+        let options = Options {
+            ignore_case: true,
+            before_context: 1,
+            ..Options::default()
+        };
+        let matcher = options.matcher("foo")?;
+        let mut collector = Collector {
+            matcher: &matcher,
+            invert_match: false,
+            max_count: options.max_count(),
+            matches: 0,
+            binary: false,
+            events: Vec::new(),
+        };
+        options
+            .searcher()
+            .search_slice(&matcher, b"a\nFoo\nb\nc\nfoo foo\n", &mut collector)?;
+
+        let summary = collector
+            .events
+            .iter()
+            .map(|event| match event {
+                Event::Match(lines) => format!("{}:{:?}", lines.line_number, lines.submatches),
+                Event::Context(lines) => format!("{}-", lines.line_number),
+                Event::Break => "--".to_string(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec!["1-", "2:[(0, 3)]", "--", "4-", "5:[(0, 3), (4, 7)]"],
+            summary
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn max_count() -> Result<(), Error> {
+        for (max, expected) in [(0, 0), (1, 1), (2, 2)] {
+            let options = Options {
+                max_count: Some(max),
+                ..Options::default()
+            };
+            let matcher = options.matcher("foo")?;
+            let mut collector = Collector {
+                matcher: &matcher,
+                invert_match: false,
+                max_count: options.max_count(),
+                matches: 0,
+                binary: false,
+                events: Vec::new(),
+            };
+            options
+                .searcher()
+                .search_slice(&matcher, b"foo\nfoo\nfoo\n", &mut collector)?;
+            assert_eq!(expected, collector.matches, "-m {}", max);
+            assert_eq!(expected as usize, collector.events.len(), "-m {}", max);
+        }
+        Ok(())
+    }
+}
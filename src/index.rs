@@ -0,0 +1,499 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::ensure;
+use anyhow::Context;
+use anyhow::Error;
+use git2::Oid;
+use rayon::iter::IntoParallelIterator;
+use rayon::iter::ParallelIterator;
+use regex_syntax::hir::Class;
+use regex_syntax::hir::Hir;
+use regex_syntax::hir::HirKind;
+
+use super::config;
+use super::git;
+use super::Cache;
+use crate::tags::TagFilter;
+use config::Spec;
+
+/// The last byte is the version; v1 wrongly indexed blobs starting with a BOM.
+const MAGIC: &[u8] = b"gitgeoff-index\x02";
+
+/// Larger blobs aren't indexed, so are always searched.
+const MAX_INDEXED: usize = 1 << 20;
+
+/// The searcher decodes text starting with these, so its trigrams aren't the blob's, and
+/// it's left unindexed, to always be searched.
+const BOMS: [&[u8]; 3] = [b"\xef\xbb\xbf", b"\xff\xfe", b"\xfe\xff"];
+
+/// Which blobs contain each trigram, case folded, for one repo's upstream, e.g.
+/// `origin/REMOTE_HEAD`.
+///
+/// Blobs the index hasn't seen are always searched, so an index for an older tree
+/// is still correct, just slower.
+pub struct Index {
+    tree: Oid,
+    blobs: Vec<Oid>,
+    /// trigram to sorted indexes into `blobs`
+    postings: HashMap<u32, Vec<u32>>,
+}
+
+/// What must appear in a file for a pattern to match it, case folded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Query {
+    All,
+    Literal(Vec<u8>),
+    And(Vec<Query>),
+    Or(Vec<Query>),
+}
+
+/// The blobs an `Index` says might match a `Query`.
+pub struct Candidates {
+    indexed: HashMap<Oid, u32>,
+    /// sorted; `None` for all of them
+    matching: Option<Vec<u32>>,
+}
+
+/// Build or refresh the index of every repo's upstream, e.g. `origin/REMOTE_HEAD`.
+pub fn index(cache: &Cache, filter: &TagFilter) -> Result<(), Error> {
+    let results = config::load_matching(filter)?
+        .into_par_iter()
+        .map(|spec| {
+            let result = refresh(cache, &spec);
+            (spec, result)
+        })
+        .collect::<Vec<(Spec, Result<Option<String>, Error>)>>();
+
+    let mut failed = 0;
+    for (spec, result) in &results {
+        match result {
+            Ok(Some(done)) => println!("{}: {}", spec.local_dir()?, done),
+            Ok(None) => (),
+            Err(e) => {
+                failed += 1;
+                println!("{}: failed: {:#}", spec.url.as_str(), e);
+            }
+        }
+    }
+
+    if failed > 0 {
+        bail!("{} of {} repos failed to index", failed, results.len());
+    }
+
+    Ok(())
+}
+
+fn refresh(cache: &Cache, spec: &Spec) -> Result<Option<String>, Error> {
+    let dest = spec.local_dir()?;
+    if !Path::new(dest).exists() {
+        return Ok(None);
+    }
+    let repo = git2::Repository::open(dest)?;
+    let upstream = spec.upstream();
+    let tree = match git::if_found(repo.revparse_single(&upstream))? {
+        Some(head) => head.peel_to_tree()?,
+        None => return Ok(Some(format!("no {}, skipping", upstream))),
+    };
+
+    let path = cache.index(&spec.url)?;
+    let previous = Index::load(&path)?;
+    if previous.as_ref().map(|index| index.tree) == Some(tree.id()) {
+        return Ok(Some("up to date".to_string()));
+    }
+
+    let (index, added) = Index::build(&repo, &tree, previous)?;
+    index.save(&path)?;
+    Ok(Some(format!(
+        "indexed {} new blobs, {} in total",
+        added,
+        index.blobs.len()
+    )))
+}
+
+impl Index {
+    /// Index `tree`, reusing whatever `previous` knows about its blobs.
+    ///
+    /// Returns how many blobs had to be read.
+    pub fn build(
+        repo: &git2::Repository,
+        tree: &git2::Tree,
+        previous: Option<Index>,
+    ) -> Result<(Index, usize), Error> {
+        let odb = repo.odb()?;
+        let mut wanted = HashSet::new();
+        tree.walk(git2::TreeWalkMode::PreOrder, |_, entry| {
+            if Some(git2::ObjectType::Blob) == entry.kind() {
+                wanted.insert(entry.id());
+            }
+            git2::TreeWalkResult::Ok
+        })?;
+
+        let mut index = Index {
+            tree: tree.id(),
+            blobs: Vec::new(),
+            postings: HashMap::new(),
+        };
+
+        if let Some(previous) = previous {
+            let mut renumber = vec![None; previous.blobs.len()];
+            for (old, oid) in previous.blobs.into_iter().enumerate() {
+                if wanted.remove(&oid) {
+                    renumber[old] = Some(index.blobs.len() as u32);
+                    index.blobs.push(oid);
+                }
+            }
+            for (trigram, blobs) in previous.postings {
+                let blobs = blobs
+                    .into_iter()
+                    .filter_map(|old| renumber[old as usize])
+                    .collect::<Vec<_>>();
+                if !blobs.is_empty() {
+                    index.postings.insert(trigram, blobs);
+                }
+            }
+        }
+
+        let mut added = 0;
+        let mut wanted = wanted.into_iter().collect::<Vec<_>>();
+        wanted.sort_unstable();
+        for oid in wanted {
+            let (size, _) = odb.read_header(oid)?;
+            if size > MAX_INDEXED {
+                continue;
+            }
+            let blob = repo.find_blob(oid)?;
+            if BOMS.iter().any(|bom| blob.content().starts_with(bom)) {
+                continue;
+            }
+            let id = index.blobs.len() as u32;
+            index.blobs.push(oid);
+            for trigram in trigrams(blob.content()) {
+                index.postings.entry(trigram).or_default().push(id);
+            }
+            added += 1;
+        }
+
+        Ok((index, added))
+    }
+
+    /// `None` if there's no index yet.
+    pub fn load(path: &Path) -> Result<Option<Index>, Error> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => Err(e)?,
+        };
+        // an older version is as good as none, and `index` replaces it
+        if data.starts_with(&MAGIC[..MAGIC.len() - 1]) && !data.starts_with(MAGIC) {
+            return Ok(None);
+        }
+        Index::parse(&data)
+            .map(Some)
+            .with_context(|| anyhow!("reading index {:?}, try removing it", path))
+    }
+
+    fn parse(data: &[u8]) -> Result<Index, Error> {
+        let mut reader = Reader { data };
+        ensure!(MAGIC == reader.take(MAGIC.len())?, "not an index");
+        let tree = Oid::from_bytes(reader.take(20)?)?;
+        let blobs = (0..reader.u32()?)
+            .map(|_| Ok(Oid::from_bytes(reader.take(20)?)?))
+            .collect::<Result<Vec<_>, Error>>()?;
+        let mut postings = HashMap::new();
+        for _ in 0..reader.u32()? {
+            let trigram = reader.u32()?;
+            let list = (0..reader.u32()?)
+                .map(|_| reader.u32())
+                .collect::<Result<Vec<_>, Error>>()?;
+            postings.insert(trigram, list);
+        }
+        ensure!(reader.data.is_empty(), "trailing data");
+        Ok(Index {
+            tree,
+            blobs,
+            postings,
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let mut temp = tempfile_fast::Sponge::new_for(path)?;
+        {
+            let mut out = io::BufWriter::new(&mut temp);
+            out.write_all(MAGIC)?;
+            out.write_all(self.tree.as_bytes())?;
+            out.write_all(&(self.blobs.len() as u32).to_le_bytes())?;
+            for blob in &self.blobs {
+                out.write_all(blob.as_bytes())?;
+            }
+            out.write_all(&(self.postings.len() as u32).to_le_bytes())?;
+            for (trigram, blobs) in &self.postings {
+                out.write_all(&trigram.to_le_bytes())?;
+                out.write_all(&(blobs.len() as u32).to_le_bytes())?;
+                for blob in blobs {
+                    out.write_all(&blob.to_le_bytes())?;
+                }
+            }
+            out.flush()?;
+        }
+        temp.commit()?;
+        Ok(())
+    }
+
+    pub fn candidates(&self, query: &Query) -> Candidates {
+        Candidates {
+            indexed: self
+                .blobs
+                .iter()
+                .enumerate()
+                .map(|(id, &oid)| (oid, id as u32))
+                .collect(),
+            matching: self.matching(query),
+        }
+    }
+
+    fn matching(&self, query: &Query) -> Option<Vec<u32>> {
+        match query {
+            Query::All => None,
+            Query::Literal(literal) => {
+                let mut found: Option<Vec<u32>> = None;
+                for trigram in trigrams(literal) {
+                    let blobs = self.postings.get(&trigram).cloned().unwrap_or_default();
+                    found = Some(match found {
+                        Some(found) => intersect(&found, &blobs),
+                        None => blobs,
+                    });
+                }
+                found
+            }
+            Query::And(queries) => queries
+                .iter()
+                .filter_map(|query| self.matching(query))
+                .reduce(|a, b| intersect(&a, &b)),
+            Query::Or(queries) => {
+                let mut found = Vec::new();
+                for query in queries {
+                    found = union(&found, &self.matching(query)?);
+                }
+                Some(found)
+            }
+        }
+    }
+}
+
+impl Candidates {
+    pub fn may_match(&self, blob: Oid) -> bool {
+        match (&self.matching, self.indexed.get(&blob)) {
+            (Some(matching), Some(id)) => matching.binary_search(id).is_ok(),
+            _ => true,
+        }
+    }
+}
+
+struct Reader<'d> {
+    data: &'d [u8],
+}
+
+impl<'d> Reader<'d> {
+    fn take(&mut self, len: usize) -> Result<&'d [u8], Error> {
+        ensure!(self.data.len() >= len, "truncated");
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+}
+
+/// Every distinct trigram in `data`, ASCII case folded.
+fn trigrams(data: &[u8]) -> HashSet<u32> {
+    data.windows(3)
+        .map(|w| {
+            u32::from(w[0].to_ascii_lowercase()) << 16
+                | u32::from(w[1].to_ascii_lowercase()) << 8
+                | u32::from(w[2].to_ascii_lowercase())
+        })
+        .collect()
+}
+
+fn intersect(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let (mut i, mut j) = (0, 0);
+    let mut out = Vec::new();
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                out.push(a[i]);
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    out
+}
+
+fn union(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut out = a.iter().chain(b).copied().collect::<Vec<_>>();
+    out.sort_unstable();
+    out.dedup();
+    out
+}
+
+impl Query {
+    /// What a regex, parsed like `grep` parses it, needs to be present to match.
+    pub fn new(pattern: &str, case_insensitive: bool) -> Result<Query, Error> {
+        let hir = regex_syntax::ParserBuilder::new()
+            .case_insensitive(case_insensitive)
+            .multi_line(true)
+            .build()
+            .parse(pattern)?;
+        Ok(Query::of(&hir).1)
+    }
+
+    /// The query, and the exact text, if the `hir` only ever matches one string.
+    fn of(hir: &Hir) -> (Option<Vec<u8>>, Query) {
+        match hir.kind() {
+            HirKind::Literal(literal) => {
+                let folded = literal.0.to_ascii_lowercase();
+                (Some(folded.clone()), Query::literal(folded))
+            }
+            HirKind::Class(class) => match single_byte(class) {
+                Some(byte) => (Some(vec![byte]), Query::All),
+                None => (None, Query::All),
+            },
+            HirKind::Empty | HirKind::Look(_) => (Some(Vec::new()), Query::All),
+            HirKind::Capture(capture) => Query::of(&capture.sub),
+            HirKind::Repetition(repetition) => {
+                if 0 == repetition.min {
+                    return (None, Query::All);
+                }
+                let (exact, query) = Query::of(&repetition.sub);
+                match exact {
+                    Some(exact) if Some(repetition.min) == repetition.max => {
+                        let exact = exact.repeat(repetition.min as usize);
+                        (Some(exact.clone()), Query::literal(exact))
+                    }
+                    _ => (None, query),
+                }
+            }
+            HirKind::Concat(subs) => {
+                let mut all_exact = true;
+                let mut run = Vec::new();
+                let mut queries = Vec::new();
+                for sub in subs {
+                    match Query::of(sub) {
+                        (Some(exact), _) => run.extend(exact),
+                        (None, query) => {
+                            all_exact = false;
+                            queries.push(Query::literal(std::mem::take(&mut run)));
+                            queries.push(query);
+                        }
+                    }
+                }
+                if all_exact {
+                    return (Some(run.clone()), Query::literal(run));
+                }
+                queries.push(Query::literal(run));
+                (None, Query::and(queries))
+            }
+            HirKind::Alternation(subs) => {
+                let queries = subs.iter().map(|sub| Query::of(sub).1).collect::<Vec<_>>();
+                if queries.contains(&Query::All) {
+                    (None, Query::All)
+                } else {
+                    (None, Query::Or(queries))
+                }
+            }
+        }
+    }
+
+    /// Strings shorter than a trigram can't narrow anything down.
+    fn literal(literal: Vec<u8>) -> Query {
+        if literal.len() < 3 {
+            Query::All
+        } else {
+            Query::Literal(literal)
+        }
+    }
+
+    fn and(queries: Vec<Query>) -> Query {
+        let mut queries = queries
+            .into_iter()
+            .filter(|query| Query::All != *query)
+            .collect::<Vec<_>>();
+        match queries.len() {
+            0 => Query::All,
+            1 => queries.remove(0),
+            _ => Query::And(queries),
+        }
+    }
+}
+
+/// The byte a class always matches, ASCII case folded, e.g. `b'k'` for `[kK]`.
+fn single_byte(class: &Class) -> Option<u8> {
+    let mut found = None;
+    let mut check = |start: u32, end: u32| -> Option<()> {
+        for c in start..=end {
+            let c = u8::try_from(c).ok().filter(u8::is_ascii)?;
+            match found {
+                None => found = Some(c.to_ascii_lowercase()),
+                Some(f) if f == c.to_ascii_lowercase() => (),
+                Some(_) => return None,
+            }
+        }
+        Some(())
+    };
+    match class {
+        Class::Unicode(class) => {
+            for range in class.ranges() {
+                check(range.start() as u32, range.end() as u32)?;
+            }
+        }
+        Class::Bytes(class) => {
+            for range in class.ranges() {
+                check(u32::from(range.start()), u32::from(range.end()))?;
+            }
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Error;
+
+    use super::Query;
+
+    fn literal(s: &str) -> Query {
+        Query::Literal(s.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn queries() -> Result<(), Error> {
+        assert_eq!(literal("hello"), Query::new("hello", false)?);
+        assert_eq!(literal("hello"), Query::new("HeLLo", true)?);
+        assert_eq!(literal("hello"), Query::new(r"\bhello\b", false)?);
+        assert_eq!(
+            Query::And(vec![literal("foo"), literal("bar")]),
+            Query::new("foo.*bar", false)?
+        );
+        assert_eq!(
+            Query::Or(vec![literal("foo"), literal("bar")]),
+            Query::new("foo|bar", false)?
+        );
+        assert_eq!(Query::All, Query::new("foo|x", false)?);
+        assert_eq!(Query::All, Query::new("(foo)?", false)?);
+        assert_eq!(literal("abab"), Query::new("(ab){2}", false)?);
+        assert_eq!(literal("foo"), Query::new("(foo)+", false)?);
+        Ok(())
+    }
+}
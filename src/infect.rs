@@ -2,25 +2,31 @@ use anyhow::Result;
 
 pub fn infect() -> Result<()> {
     let repo = git2::Repository::open(".")?;
-    add_if_missing(&repo)?;
+    add_if_missing(&repo, "origin")?;
     Ok(())
 }
 
-fn add_if_missing(repo: &git2::Repository) -> Result<()> {
-    if !fetches_remote_head(repo)? {
-        repo.remote_add_fetch("origin", "+HEAD:refs/remotes/origin/REMOTE_HEAD")?;
+pub fn add_if_missing(repo: &git2::Repository, remote: &str) -> Result<()> {
+    if !fetches_remote_head(repo, remote)? {
+        repo.remote_add_fetch(remote, &refspec(remote))?;
     }
     Ok(())
 }
 
-pub fn fetches_remote_head(repo: &git2::Repository) -> Result<bool> {
+pub fn fetches_remote_head(repo: &git2::Repository, remote: &str) -> Result<bool> {
     let config = repo.config()?;
-    let mut entries = config.entries(Some("remote.origin.fetch"))?;
+    let refspec = refspec(remote);
+    let mut entries = config.entries(Some(&format!("remote.{}.fetch", remote)))?;
     while let Some(entry) = entries.next() {
-        if entry?.value().unwrap_or("") == "+HEAD:refs/remotes/origin/REMOTE_HEAD" {
+        if entry?.value().unwrap_or("") == refspec {
             return Ok(true);
         }
     }
 
     Ok(false)
 }
+
+/// Fetch the remote's default branch as `<remote>/REMOTE_HEAD`.
+fn refspec(remote: &str) -> String {
+    format!("+HEAD:refs/remotes/{}/REMOTE_HEAD", remote)
+}
@@ -1,26 +1,43 @@
 use std::env;
+use std::path::PathBuf;
 
 use anyhow::Error;
 use clap::ArgAction;
+use clap::ArgGroup;
 
+mod attributes;
 mod cache;
+mod clone;
 mod config;
+mod credentials;
+mod edit;
+mod fetch;
 mod git;
 mod git_url;
 #[cfg(github)]
 mod github;
 mod grep;
+mod index;
 mod infect;
+mod pickaxe;
+mod progress;
+mod remotes;
+mod replace;
+mod scan;
 mod status;
+mod tags;
 
 use cache::Cache;
+use tags::TagFilter;
 
 fn main() -> Result<(), Error> {
-    pretty_env_logger::formatted_builder()
+    let logger = pretty_env_logger::formatted_builder()
         .filter_level(log::LevelFilter::Info)
-        .init();
+        .build();
+    log::set_max_level(logger.filter());
+    log::set_boxed_logger(Box::new(progress::Logger::new(Box::new(logger))))?;
 
-    let _cache = Cache::new()?;
+    let cache = Cache::new()?;
 
     use clap::Arg;
     use clap::Command;
@@ -30,8 +47,25 @@ fn main() -> Result<(), Error> {
                 .long("tags")
                 .short('t')
                 .value_name("tags")
+                .help(
+                    "Only operate on repos matching these tags, e.g. `backend,!archived|@untagged`, \
+                    from anywhere in the workspace, like --all",
+                )
                 .required(false)
-                .num_args(..),
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .value_name("path")
+                .help("Use this config, instead of $GITGEOFF_CONFIG, or the nearest .gitgeoff")
+                .value_parser(clap::value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("all")
+                .long("all")
+                .help("Operate on every repo, even when run inside one, as --tags does")
+                .action(ArgAction::SetTrue),
         )
         .subcommand(
             Command::new("status")
@@ -41,21 +75,323 @@ fn main() -> Result<(), Error> {
                         .long("update")
                         .short('u')
                         .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("retry")
+                        .long("retry")
+                        .value_name("N")
+                        .help("Retry failing fetches up to N times")
+                        .value_parser(clap::value_parser!(u32))
+                        .default_value("0"),
+                )
+                .arg(
+                    Arg::new("failed")
+                        .long("failed")
+                        .help("Only update the repos which failed to update last time")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("verbose")
+                        .long("verbose")
+                        .short('v')
+                        .help("List every changed file")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("branches")
+                        .long("branches")
+                        .help("Check every local branch, and stashes, for forgotten work")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .value_parser(["text", "json", "ndjson"])
+                        .default_value("text"),
                 ),
         )
         .subcommand(
             Command::new("grep")
                 .about("Search for text in all child repos")
                 .arg(Arg::new("pattern").required(true))
-                .arg(Arg::new("globs").num_args(1..)),
+                .arg(Arg::new("globs").num_args(1..))
+                .arg(
+                    Arg::new("worktree")
+                        .long("worktree")
+                        .help("Search the files on disk, instead of each repo's upstream")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("cached")
+                        .long("cached")
+                        .help("Search the index, instead of each repo's upstream")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("rev")
+                        .long("rev")
+                        .value_name("revision")
+                        .help("Search this commit, instead of each repo's upstream"),
+                )
+                .group(ArgGroup::new("target").args(["worktree", "cached", "rev"]))
+                .arg(
+                    Arg::new("log")
+                        .long("log")
+                        .help(
+                            "Search history for commits which add or remove the pattern, \
+                             like `git log -S`, or which change a matching line, with `--log=G`",
+                        )
+                        .value_parser(["S", "G"])
+                        .num_args(0..=1)
+                        .require_equals(true)
+                        .default_missing_value("S")
+                        .conflicts_with_all(["target", "output"]),
+                )
+                .arg(
+                    Arg::new("since")
+                        .long("since")
+                        .value_name("date")
+                        .help("With --log, only commits after e.g. `2020-01-31` or `2weeks` (ago)")
+                        .requires("log"),
+                )
+                .arg(
+                    Arg::new("until")
+                        .long("until")
+                        .value_name("date")
+                        .requires("log"),
+                )
+                .arg(
+                    Arg::new("author")
+                        .long("author")
+                        .value_name("regex")
+                        .help("With --log, only commits with a matching author name or email")
+                        .requires("log"),
+                )
+                .arg(flag("ignore-case", 'i', "Case insensitive search"))
+                .arg(flag(
+                    "smart-case",
+                    'S',
+                    "Case insensitive search, unless the pattern has upper case",
+                ))
+                .arg(flag(
+                    "fixed-strings",
+                    'F',
+                    "Treat the pattern as a literal string, not a regex",
+                ))
+                .arg(flag("word-regexp", 'w', "Only match whole words"))
+                .arg(flag("invert-match", 'v', "Show lines which don't match"))
+                .arg(flag("multiline", 'U', "Allow matches to span lines"))
+                .arg(count(
+                    "after-context",
+                    'A',
+                    "Show NUM lines after each match",
+                ))
+                .arg(count(
+                    "before-context",
+                    'B',
+                    "Show NUM lines before each match",
+                ))
+                .arg(count("context", 'C', "Show NUM lines around each match"))
+                .arg(count(
+                    "max-count",
+                    'm',
+                    "Stop after NUM matches in each file",
+                ))
+                .arg(flag(
+                    "files-with-matches",
+                    'l',
+                    "Only list the files with a match",
+                ))
+                .arg(flag(
+                    "count",
+                    'c',
+                    "Only show the number of matching lines in each file",
+                ))
+                .arg(
+                    Arg::new("summary")
+                        .long("summary")
+                        .help("Only show the number of matching lines and files in each repo")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .help("Print ripgrep's JSON Lines messages, with the repo and a link")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(flag(
+                    "text",
+                    'a',
+                    "Search files with NUL bytes in, which are skipped as binary by default",
+                ))
+                .arg(
+                    Arg::new("max-filesize")
+                        .long("max-filesize")
+                        .value_name("NUM+SUFFIX?")
+                        .help("Skip files larger than this, e.g. `100K` or `2M`")
+                        .value_parser(grep::parse_size),
+                )
+                .arg(
+                    Arg::new("threads")
+                        .long("threads")
+                        .short('j')
+                        .value_name("NUM")
+                        .help("Search with this many threads, instead of one per CPU")
+                        .value_parser(clap::value_parser!(usize)),
+                )
+                .group(ArgGroup::new("output").args([
+                    "files-with-matches",
+                    "count",
+                    "summary",
+                    "json",
+                ])),
+        )
+        .subcommand(
+            Command::new("replace")
+                .about("Replace a pattern in the files on disk in all child repos")
+                .arg(Arg::new("pattern").required(true))
+                .arg(
+                    Arg::new("replacement")
+                        .required(true)
+                        .help("The new text, which can use groups from the pattern, e.g. `$1`"),
+                )
+                .arg(Arg::new("globs").num_args(1..))
+                .arg(flag("ignore-case", 'i', "Case insensitive search"))
+                .arg(flag(
+                    "smart-case",
+                    'S',
+                    "Case insensitive search, unless the pattern has upper case",
+                ))
+                .arg(flag(
+                    "fixed-strings",
+                    'F',
+                    "Treat the pattern as a literal string, not a regex",
+                ))
+                .arg(flag("word-regexp", 'w', "Only match whole words"))
+                .arg(flag("multiline", 'U', "Allow matches to span lines"))
+                .arg(flag(
+                    "text",
+                    'a',
+                    "Replace in files with NUL bytes in, which are skipped as binary by default",
+                ))
+                .arg(
+                    Arg::new("max-filesize")
+                        .long("max-filesize")
+                        .value_name("NUM+SUFFIX?")
+                        .help("Skip files larger than this, e.g. `100K` or `2M`")
+                        .value_parser(grep::parse_size),
+                )
+                .arg(
+                    Arg::new("write")
+                        .long("write")
+                        .help("Change the files, instead of showing what would change")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("index").about(
+                "Build or refresh the index grep uses to skip files, for each repo's upstream",
+            ),
+        )
+        .subcommand(
+            Command::new("clone")
+                .visible_alias("update")
+                .about("Clone any absent child repos")
+                .arg(
+                    Arg::new("no-mirror")
+                        .long("no-mirror")
+                        .help(
+                            "Clone directly, instead of sharing objects with a mirror in the cache",
+                        )
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("fix-remotes")
+                .about("Set the remote to the url from .gitgeoff, where it doesn't match"),
+        )
+        .subcommand(
+            Command::new("scan")
+                .about("List git repos next to .gitgeoff which aren't in it")
+                .arg(
+                    Arg::new("add")
+                        .long("add")
+                        .help("Append them to .gitgeoff, with the tags of their neighbours")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("add")
+                .about("Add a repo to .gitgeoff")
+                .arg(Arg::new("url").required(true))
+                .arg(Arg::new("tags").num_args(1..))
+                .arg(
+                    Arg::new("clone")
+                        .long("clone")
+                        .help("Clone it straight away")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("remove")
+                .about("Remove a repo from .gitgeoff, by its directory or url")
+                .arg(Arg::new("name").required(true))
+                .arg(
+                    Arg::new("delete")
+                        .long("delete")
+                        .help("Also delete the checkout, if it has nothing unpushed")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("tag")
+                .about("Change a repo's tags in .gitgeoff, e.g. `tag geoff +tools -old`")
+                .arg(Arg::new("name").required(true))
+                .arg(
+                    Arg::new("changes")
+                        .required(true)
+                        .num_args(1..)
+                        .allow_hyphen_values(true),
+                ),
+        )
+        .subcommand(
+            Command::new("check")
+                .about("Report repos listed twice, clashing checkouts, and unusable tags"),
         )
         .subcommand(Command::new("infect").about("Add .git/config gitgeoff depends upon"))
         .subcommand_required(true)
         .get_matches();
 
+    let filter = TagFilter::parse(
+        &matches
+            .get_many::<String>("tags")
+            .map(|v| v.collect::<Vec<&String>>())
+            .unwrap_or_default(),
+    )?;
+
+    // `infect` works on the repo it's run in, which needn't be in a workspace; choosing repos by
+    // tag means choosing from the whole workspace, not just the one we're in
+    if matches.subcommand_name() != Some("infect") {
+        config::enter(
+            matches.get_one::<PathBuf>("config").map(PathBuf::as_path),
+            matches.get_flag("all") || !filter.is_empty(),
+        )?;
+    }
+
     match matches.subcommand() {
         Some(("status", args)) => {
-            status::status(args.get_flag("update"))?;
+            let options = status::Options {
+                update: args.get_flag("update"),
+                retries: *args.get_one::<u32>("retry").expect("defaulted"),
+                only_failed: args.get_flag("failed"),
+                format: args
+                    .get_one::<String>("format")
+                    .expect("defaulted")
+                    .parse()?,
+                verbose: args.get_flag("verbose"),
+                branches: args.get_flag("branches"),
+            };
+            status::status(&cache, &filter, &options)?;
         }
         Some(("grep", args)) => {
             let pattern = args.get_one::<String>("pattern").expect("required");
@@ -63,20 +399,167 @@ fn main() -> Result<(), Error> {
                 .get_many::<String>("globs")
                 .map(|v| v.into_iter().collect::<Vec<&String>>())
                 .unwrap_or_default();
-            grep::grep(pattern, &globs)?;
+            if let Some(&threads) = args.get_one::<usize>("threads") {
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .build_global()?;
+            }
+            let context = args.get_one::<usize>("context").copied();
+            let matching = grep::Options {
+                ignore_case: args.get_flag("ignore-case"),
+                smart_case: args.get_flag("smart-case"),
+                fixed_strings: args.get_flag("fixed-strings"),
+                word_regexp: args.get_flag("word-regexp"),
+                invert_match: args.get_flag("invert-match"),
+                before_context: args
+                    .get_one::<usize>("before-context")
+                    .copied()
+                    .or(context)
+                    .unwrap_or(0),
+                after_context: args
+                    .get_one::<usize>("after-context")
+                    .copied()
+                    .or(context)
+                    .unwrap_or(0),
+                max_count: args.get_one::<usize>("max-count").map(|&n| n as u64),
+                multiline: args.get_flag("multiline"),
+                text: args.get_flag("text"),
+                max_filesize: args.get_one::<u64>("max-filesize").copied(),
+                output: if args.get_flag("files-with-matches") {
+                    grep::Output::Files
+                } else if args.get_flag("count") {
+                    grep::Output::Count
+                } else if args.get_flag("summary") {
+                    grep::Output::Summary
+                } else if args.get_flag("json") {
+                    grep::Output::Json
+                } else {
+                    grep::Output::Lines
+                },
+            };
+            if let Some(mode) = args.get_one::<String>("log") {
+                let author = args
+                    .get_one::<String>("author")
+                    .map(|author| regex::bytes::Regex::new(author))
+                    .transpose()?;
+                let options = pickaxe::Options {
+                    mode: match mode.as_str() {
+                        "G" => pickaxe::Mode::Lines,
+                        _ => pickaxe::Mode::Count,
+                    },
+                    since: args
+                        .get_one::<String>("since")
+                        .map(|date| pickaxe::parse_date(date))
+                        .transpose()?,
+                    until: args
+                        .get_one::<String>("until")
+                        .map(|date| pickaxe::parse_date(date))
+                        .transpose()?,
+                    author: author.as_ref(),
+                };
+                pickaxe::log(&filter, pattern, &globs, &matching, &options)?;
+            } else {
+                let target = if args.get_flag("worktree") {
+                    grep::Target::Worktree
+                } else if args.get_flag("cached") {
+                    grep::Target::Index
+                } else {
+                    match args.get_one::<String>("rev") {
+                        Some(rev) => grep::Target::Rev(rev.to_string()),
+                        None => grep::Target::Upstream,
+                    }
+                };
+                grep::grep(&cache, &filter, &target, pattern, &globs, &matching)?;
+            }
+        }
+        Some(("replace", args)) => {
+            let globs = args
+                .get_many::<String>("globs")
+                .map(|v| v.into_iter().collect::<Vec<&String>>())
+                .unwrap_or_default();
+            let matching = grep::Options {
+                ignore_case: args.get_flag("ignore-case"),
+                smart_case: args.get_flag("smart-case"),
+                fixed_strings: args.get_flag("fixed-strings"),
+                word_regexp: args.get_flag("word-regexp"),
+                multiline: args.get_flag("multiline"),
+                text: args.get_flag("text"),
+                max_filesize: args.get_one::<u64>("max-filesize").copied(),
+                ..grep::Options::default()
+            };
+            replace::replace(
+                &filter,
+                args.get_one::<String>("pattern").expect("required"),
+                args.get_one::<String>("replacement").expect("required"),
+                &globs,
+                &matching,
+                args.get_flag("write"),
+            )?;
+        }
+        Some(("index", _)) => {
+            index::index(&cache, &filter)?;
+        }
+        Some(("clone", args)) => {
+            clone::clone(&cache, &filter, !args.get_flag("no-mirror"))?;
+        }
+        Some(("fix-remotes", _)) => {
+            remotes::fix_remotes(&filter)?;
+        }
+        Some(("scan", args)) => {
+            scan::scan(args.get_flag("add"))?;
+        }
+        Some(("add", args)) => {
+            let tags = args
+                .get_many::<String>("tags")
+                .map(|v| v.map(|tag| tag.as_str()).collect::<Vec<&str>>())
+                .unwrap_or_default();
+            edit::add(
+                &cache,
+                args.get_one::<String>("url").expect("required"),
+                &tags,
+                args.get_flag("clone"),
+            )?;
+        }
+        Some(("remove", args)) => {
+            edit::remove(
+                args.get_one::<String>("name").expect("required"),
+                args.get_flag("delete"),
+            )?;
+        }
+        Some(("tag", args)) => {
+            let changes = args
+                .get_many::<String>("changes")
+                .expect("required")
+                .map(|change| change.as_str())
+                .collect::<Vec<&str>>();
+            edit::tag(args.get_one::<String>("name").expect("required"), &changes)?;
+        }
+        Some(("check", _)) => {
+            config::check()?;
         }
         Some(("infect", _)) => {
             infect::infect()?;
         }
-        Some(("update", _args)) =>
-        {
-            #[cfg(never)]
-            git::clone_or_fetch(&src, &dest)
-                .with_context(|| anyhow!("ensure {:?} -> {:?}", src, dest))
-        }
         Some((unknown_command, _args)) => unreachable!("unknown command: {:?}", unknown_command),
         _ => unreachable!("subcommand required"),
     }
 
     Ok(())
 }
+
+fn flag(name: &'static str, short: char, help: &'static str) -> clap::Arg {
+    clap::Arg::new(name)
+        .long(name)
+        .short(short)
+        .help(help)
+        .action(ArgAction::SetTrue)
+}
+
+fn count(name: &'static str, short: char, help: &'static str) -> clap::Arg {
+    clap::Arg::new(name)
+        .long(name)
+        .short(short)
+        .value_name("NUM")
+        .help(help)
+        .value_parser(clap::value_parser!(usize))
+}
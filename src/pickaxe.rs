@@ -0,0 +1,346 @@
+use std::path::Path;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Error;
+use git2::FileMode;
+use log::error;
+use log::warn;
+use rayon::iter::IntoParallelIterator;
+use rayon::iter::ParallelIterator;
+use regex::bytes::Regex;
+
+use super::config;
+use super::git;
+use super::grep;
+use crate::git_url::Provider;
+use crate::tags::TagFilter;
+use config::Spec;
+
+/// What makes a commit interesting, like `git log -S` or `git log -G`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// the number of matches in a file changed, i.e. the string was added or removed
+    Count,
+    /// an added or removed line matches
+    Lines,
+}
+
+pub struct Options<'a> {
+    pub mode: Mode,
+    pub since: Option<SystemTime>,
+    pub until: Option<SystemTime>,
+    pub author: Option<&'a Regex>,
+}
+
+/// Search the history of every repo's upstream, e.g. `origin/REMOTE_HEAD`, for commits adding or removing
+/// `pattern`.
+pub fn log(
+    filter: &TagFilter,
+    pattern: &str,
+    globs: &[&String],
+    matching: &grep::Options,
+    options: &Options,
+) -> Result<(), Error> {
+    let globs = grep::build_globs(globs)?;
+    let pattern = matching.bytes_regex(pattern)?;
+
+    let found = config::load_matching(filter)?
+        .into_par_iter()
+        .map(|s: Spec| {
+            let label = s.local_dir().unwrap_or(s.url.as_str()).to_string();
+            (label, s.upstream(), log_repo(&s, &pattern, &globs, options))
+        })
+        .collect::<Vec<(String, String, Result<Option<Vec<String>>, Error>)>>();
+
+    let mut failed = 0;
+    for (label, upstream, found) in &found {
+        match found {
+            Ok(Some(lines)) => {
+                for line in lines {
+                    println!("{}", line);
+                }
+            }
+            Ok(None) => warn!("{}: no {}, skipped", label, upstream),
+            Err(e) => {
+                failed += 1;
+                error!("{}: searching history: {:#}", label, e);
+            }
+        }
+    }
+
+    if failed > 0 {
+        bail!("{} of {} repos had errors", failed, found.len());
+    }
+    Ok(())
+}
+
+/// `None` if there's no upstream, e.g. `origin/REMOTE_HEAD`, to search.
+fn log_repo(
+    spec: &Spec,
+    pattern: &Regex,
+    globs: &globset::GlobSet,
+    options: &Options,
+) -> Result<Option<Vec<String>>, Error> {
+    let dest = spec.local_dir()?;
+    if !Path::new(dest).exists() {
+        return Ok(Some(Vec::new()));
+    }
+    let repo = git2::Repository::open(dest)?;
+    log_in(
+        pattern,
+        &spec.upstream(),
+        dest,
+        spec.url.provider().as_ref(),
+        globs,
+        options,
+        &repo,
+    )
+}
+
+fn log_in(
+    pattern: &Regex,
+    rev: &str,
+    prefix: &str,
+    provider: Option<&Provider>,
+    globs: &globset::GlobSet,
+    options: &Options,
+    repo: &git2::Repository,
+) -> Result<Option<Vec<String>>, Error> {
+    let head = match git::if_found(repo.revparse_single(rev))? {
+        Some(head) => head.peel_to_commit()?.id(),
+        None => return Ok(None),
+    };
+
+    let mut walk = repo.revwalk()?;
+    walk.set_sorting(git2::Sort::TIME)?;
+    walk.push(head)?;
+
+    let mut found = Vec::new();
+    for oid in walk {
+        let commit = repo.find_commit(oid?)?;
+        let when = UNIX_EPOCH + Duration::from_secs(commit.time().seconds().max(0) as u64);
+        if options.until.is_some_and(|until| when > until) {
+            continue;
+        }
+        if options.since.is_some_and(|since| when < since) {
+            // sorted by time, so nothing older will match either
+            break;
+        }
+        let author = commit.author();
+        if let Some(wanted) = options.author {
+            if !wanted.is_match(author.name_bytes()) && !wanted.is_match(author.email_bytes()) {
+                continue;
+            }
+        }
+
+        let paths = changed_paths(repo, &commit, pattern, globs, options.mode)?;
+        if paths.is_empty() {
+            continue;
+        }
+
+        let id = commit.id().to_string();
+        let short = provider
+            .map(|p| grep::href(&id[..10], &p.html_commit(&id)))
+            .unwrap_or_else(|| id[..10].to_string());
+        let date = humantime::format_rfc3339_seconds(when).to_string();
+        for path in paths {
+            found.push(format!(
+                "{} {} {} {}/{}",
+                short,
+                &date[..10],
+                String::from_utf8_lossy(author.name_bytes()),
+                prefix,
+                path
+            ));
+        }
+    }
+
+    Ok(Some(found))
+}
+
+/// The files in `commit` (compared to its parent) where `pattern` was added or removed.
+///
+/// Merges are skipped, like `git log -S`, as their changes are reported on the commits merged.
+fn changed_paths(
+    repo: &git2::Repository,
+    commit: &git2::Commit,
+    pattern: &Regex,
+    globs: &globset::GlobSet,
+    mode: Mode,
+) -> Result<Vec<String>, Error> {
+    if commit.parent_count() > 1 {
+        return Ok(Vec::new());
+    }
+    let old_tree = match commit.parents().next() {
+        Some(parent) => Some(parent.tree()?),
+        None => None,
+    };
+    let diff = repo.diff_tree_to_tree(old_tree.as_ref(), Some(&commit.tree()?), None)?;
+
+    let path_of = |delta: &git2::DiffDelta| -> String {
+        delta
+            .new_file()
+            .path()
+            .or_else(|| delta.old_file().path())
+            .map(|p| p.to_string_lossy().to_string())
+            .unwrap_or_default()
+    };
+    let wanted = |path: &str| globs.is_empty() || globs.is_match(path);
+
+    let mut paths = Vec::new();
+    match mode {
+        Mode::Count => {
+            for delta in diff.deltas() {
+                let path = path_of(&delta);
+                if !wanted(&path) || !is_file(&delta) {
+                    continue;
+                }
+                let old = count_in(repo, delta.old_file().id(), pattern)?;
+                let new = count_in(repo, delta.new_file().id(), pattern)?;
+                if old != new {
+                    paths.push(path);
+                }
+            }
+        }
+        Mode::Lines => {
+            diff.foreach(
+                &mut |_, _| true,
+                None,
+                None,
+                Some(&mut |delta, _, line| {
+                    let path = path_of(&delta);
+                    if matches!(line.origin(), '+' | '-')
+                        && paths.last() != Some(&path)
+                        && is_file(&delta)
+                        && wanted(&path)
+                        && pattern.is_match(line.content())
+                    {
+                        paths.push(path);
+                    }
+                    true
+                }),
+            )?;
+        }
+    }
+
+    Ok(paths)
+}
+
+/// Not a submodule, whose "content" is a commit id, not a blob.
+fn is_file(delta: &git2::DiffDelta) -> bool {
+    [delta.old_file(), delta.new_file()].iter().all(|file| {
+        matches!(
+            file.mode(),
+            FileMode::Unreadable
+                | FileMode::Blob
+                | FileMode::BlobGroupWritable
+                | FileMode::BlobExecutable
+                | FileMode::Link
+        )
+    })
+}
+
+fn count_in(repo: &git2::Repository, blob: git2::Oid, pattern: &Regex) -> Result<usize, Error> {
+    if blob.is_zero() {
+        return Ok(0);
+    }
+    Ok(pattern.find_iter(repo.find_blob(blob)?.content()).count())
+}
+
+/// `2020-01-31`, `2020-01-31 12:00:00`, or a duration ago, like `2weeks`.
+pub fn parse_date(date: &str) -> Result<SystemTime, Error> {
+    if let Ok(ago) = humantime::parse_duration(date) {
+        return Ok(SystemTime::now() - ago);
+    }
+    let date = if 10 == date.len() {
+        format!("{} 00:00:00", date)
+    } else {
+        date.to_string()
+    };
+    humantime::parse_rfc3339_weak(&date).with_context(|| anyhow!("parsing date {:?}", date))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use std::time::UNIX_EPOCH;
+
+    use anyhow::Error;
+
+    use super::log_in;
+    use super::parse_date;
+    use super::Mode;
+    use super::Options;
+
+    #[test]
+    fn dates() -> Result<(), Error> {
+        assert_eq!(
+            UNIX_EPOCH + Duration::from_secs(1_580_428_800),
+            parse_date("2020-01-31")?
+        );
+        assert_eq!(
+            UNIX_EPOCH + Duration::from_secs(1_580_472_000),
+            parse_date("2020-01-31 12:00:00")?
+        );
+        assert!(parse_date("2weeks")? < parse_date("1week")?);
+        assert!(parse_date("yesterday").is_err());
+        Ok(())
+    }
+
+    /// A commit with `content` in `f`, and a submodule at `sub`.
+    fn commit<'r>(
+        repo: &'r git2::Repository,
+        parents: &[&git2::Commit],
+        content: &str,
+        sub: git2::Oid,
+    ) -> Result<git2::Commit<'r>, Error> {
+        let mut tree = repo.treebuilder(None)?;
+        tree.insert("f", repo.blob(content.as_bytes())?, 0o100644)?;
+        tree.insert("sub", sub, 0o160000)?;
+        let tree = repo.find_tree(tree.write()?)?;
+        let sig = git2::Signature::now("a", "a@example.com")?;
+        let id = repo.commit(None, &sig, &sig, content, &tree, parents)?;
+        Ok(repo.find_commit(id)?)
+    }
+
+    #[test]
+    fn merges_and_submodules() -> Result<(), Error> {
+        let dir = tempfile::tempdir()?;
+        let repo = git2::Repository::init_bare(dir.path())?;
+        let sub = |n: u8| git2::Oid::from_bytes(&[n; 20]);
+
+        let base = commit(&repo, &[], "base\n", sub(1)?)?;
+        let side = commit(&repo, &[&base], "base\nneedle\n", sub(1)?)?;
+        let main = commit(&repo, &[&base], "base\n", sub(2)?)?;
+        let merge = commit(&repo, &[&main, &side], "base\nneedle\n", sub(2)?)?;
+        repo.reference("refs/heads/main", merge.id(), false, "test")?;
+
+        let pattern = regex::bytes::Regex::new("needle|0101")?;
+        for mode in [Mode::Count, Mode::Lines] {
+            let options = Options {
+                mode,
+                since: None,
+                until: None,
+                author: None,
+            };
+            let found = log_in(
+                &pattern,
+                "main",
+                "repo",
+                None,
+                &globset::GlobSet::empty(),
+                &options,
+                &repo,
+            )?
+            .expect("rev exists");
+            assert_eq!(1, found.len(), "{:?}: {:?}", mode, found);
+            assert!(found[0].starts_with(&side.id().to_string()[..10]));
+        }
+        Ok(())
+    }
+}
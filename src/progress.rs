@@ -0,0 +1,318 @@
+use std::env;
+use std::io;
+use std::io::IsTerminal;
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use log::info;
+use log::Log;
+
+use crate::git::Progress;
+
+const REDRAW_EVERY: Duration = Duration::from_millis(100);
+const BAR_WIDTH: usize = 30;
+
+/// The display being drawn, if any, for `Logger` to keep log lines out of.
+static LIVE: Mutex<Option<Arc<Mutex<State>>>> = Mutex::new(None);
+
+/// Shows how far along every in-flight fetch is.
+///
+/// On a terminal, that's one line per repo and an overall bar, redrawn in place.
+/// Otherwise, it's a log line when each repo starts and finishes.
+pub struct Display {
+    live: bool,
+    state: Arc<Mutex<State>>,
+}
+
+/// Writes log lines above the live `Display`, instead of over it.
+pub struct Logger {
+    inner: Box<dyn Log>,
+}
+
+#[derive(Default)]
+struct State {
+    total: usize,
+    in_flight: Vec<(String, Counts)>,
+    done: usize,
+    bytes_done: usize,
+    drawn: usize,
+    last_draw: Option<Instant>,
+}
+
+#[derive(Default, Clone)]
+struct Counts {
+    received_objects: usize,
+    total_objects: usize,
+    indexed_deltas: usize,
+    total_deltas: usize,
+    received_bytes: usize,
+    message: String,
+}
+
+/// One repo's fetch; finishes when dropped.
+pub struct Repo<'d> {
+    display: &'d Display,
+    label: String,
+}
+
+impl Display {
+    pub fn new(total: usize) -> Display {
+        let live = io::stdout().is_terminal();
+        let state = Arc::new(Mutex::new(State {
+            total,
+            ..State::default()
+        }));
+        if live {
+            *LIVE.lock().expect("poisoned") = Some(Arc::clone(&state));
+        }
+        Display { live, state }
+    }
+
+    pub fn start(&self, label: &str) -> Repo<'_> {
+        if self.live {
+            let mut state = self.state.lock().expect("poisoned");
+            state.in_flight.push((label.to_string(), Counts::default()));
+            state.draw();
+        } else {
+            info!("{}: fetching", label);
+        }
+
+        Repo {
+            display: self,
+            label: label.to_string(),
+        }
+    }
+
+    /// Count a repo which didn't need fetching.
+    pub fn skip(&self) {
+        let mut state = self.state.lock().expect("poisoned");
+        state.done += 1;
+        if self.live && state.drawn > 0 {
+            state.draw();
+        }
+    }
+
+    /// Remove the display, ready for normal output.
+    pub fn finish(&self) {
+        let mut live = LIVE.lock().expect("poisoned");
+        if live
+            .as_ref()
+            .is_some_and(|state| Arc::ptr_eq(state, &self.state))
+        {
+            *live = None;
+        }
+        self.state.lock().expect("poisoned").clear();
+    }
+
+    fn update(&self, label: &str, progress: Progress) {
+        if !self.live {
+            return;
+        }
+
+        let mut state = self.state.lock().expect("poisoned");
+        if let Some((_, counts)) = state.in_flight.iter_mut().find(|(l, _)| l == label) {
+            match progress {
+                Progress::Sideband(message) => {
+                    if let Some(line) = message.lines().rev().find(|l| !l.trim().is_empty()) {
+                        counts.message = line.trim().to_string();
+                    }
+                }
+                Progress::Transfer(
+                    [_local, received, _indexed, total, indexed_deltas, total_deltas, bytes],
+                ) => {
+                    counts.received_objects = received;
+                    counts.total_objects = total;
+                    counts.indexed_deltas = indexed_deltas;
+                    counts.total_deltas = total_deltas;
+                    counts.received_bytes = bytes;
+                }
+            }
+        }
+
+        let due = state
+            .last_draw
+            .map(|last| last.elapsed() >= REDRAW_EVERY)
+            .unwrap_or(true);
+        if due {
+            state.draw();
+        }
+    }
+
+    fn end(&self, label: &str) {
+        let mut state = self.state.lock().expect("poisoned");
+        let counts = match state.in_flight.iter().position(|(l, _)| l == label) {
+            Some(pos) => state.in_flight.remove(pos).1,
+            None => Counts::default(),
+        };
+        state.done += 1;
+        state.bytes_done += counts.received_bytes;
+
+        if self.live {
+            state.draw();
+        } else {
+            info!("{}: done", label);
+        }
+    }
+}
+
+impl Drop for Display {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+impl State {
+    /// Take the display off the screen, so something else can be printed where it was.
+    fn clear(&mut self) {
+        if self.drawn > 0 {
+            print!("\x1b[{}A\x1b[J", self.drawn);
+            let _ = io::stdout().flush();
+            self.drawn = 0;
+        }
+    }
+
+    fn draw(&mut self) {
+        let width = env::var("COLUMNS")
+            .ok()
+            .and_then(|cols| cols.parse().ok())
+            .unwrap_or(80usize)
+            .saturating_sub(1);
+
+        let mut out = String::new();
+        if self.drawn > 0 {
+            out.push_str(&format!("\x1b[{}A", self.drawn));
+        }
+
+        let mut lines = Vec::with_capacity(self.in_flight.len() + 1);
+        for (label, counts) in &self.in_flight {
+            lines.push(format!("{}: {}", label, counts));
+        }
+        let in_flight_bytes: usize = self
+            .in_flight
+            .iter()
+            .map(|(_, counts)| counts.received_bytes)
+            .sum();
+        lines.push(bar(
+            self.done,
+            self.total,
+            self.bytes_done + in_flight_bytes,
+        ));
+
+        for line in &lines {
+            out.push_str("\x1b[2K");
+            out.extend(line.chars().take(width));
+            out.push('\n');
+        }
+
+        // clear whatever was left below from a longer, earlier draw
+        if lines.len() < self.drawn {
+            let extra = self.drawn - lines.len();
+            for _ in 0..extra {
+                out.push_str("\x1b[2K\n");
+            }
+            out.push_str(&format!("\x1b[{}A", extra));
+        }
+
+        print!("{}", out);
+        let _ = io::stdout().flush();
+        self.drawn = lines.len();
+        self.last_draw = Some(Instant::now());
+    }
+}
+
+impl Logger {
+    pub fn new(inner: Box<dyn Log>) -> Logger {
+        Logger { inner }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.inner.enabled(record.metadata()) {
+            return;
+        }
+        let live = LIVE.lock().expect("poisoned").clone();
+        let live = match live {
+            Some(live) => live,
+            None => return self.inner.log(record),
+        };
+        let mut state = live.lock().expect("poisoned");
+        let drawn = state.drawn > 0;
+        state.clear();
+        self.inner.log(record);
+        self.inner.flush();
+        if drawn {
+            state.draw();
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+impl Repo<'_> {
+    pub fn update(&self, progress: Progress) {
+        self.display.update(&self.label, progress);
+    }
+}
+
+impl Drop for Repo<'_> {
+    fn drop(&mut self) {
+        self.display.end(&self.label);
+    }
+}
+
+impl std::fmt::Display for Counts {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if 0 == self.total_objects {
+            return write!(f, "{}", self.message);
+        }
+        write!(
+            f,
+            "objects {}/{}, deltas {}/{}, {}",
+            self.received_objects,
+            self.total_objects,
+            self.indexed_deltas,
+            self.total_deltas,
+            human_bytes(self.received_bytes)
+        )
+    }
+}
+
+fn bar(done: usize, total: usize, bytes: usize) -> String {
+    let filled = (BAR_WIDTH * done)
+        .checked_div(total)
+        .unwrap_or(BAR_WIDTH)
+        .min(BAR_WIDTH);
+    format!(
+        "[{}{}] {}/{} repos, {}",
+        "#".repeat(filled),
+        " ".repeat(BAR_WIDTH - filled),
+        done,
+        total,
+        human_bytes(bytes)
+    )
+}
+
+fn human_bytes(bytes: usize) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024. && unit + 1 < UNITS.len() {
+        value /= 1024.;
+        unit += 1;
+    }
+    if 0 == unit {
+        format!("{} {}", bytes, UNITS[unit])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
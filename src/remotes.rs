@@ -0,0 +1,38 @@
+use std::path::Path;
+
+use anyhow::Error;
+
+use super::config;
+use super::git;
+use crate::tags::TagFilter;
+
+/// Point the remote back at the url from `.gitgeoff`, wherever `status` says it's wrong.
+pub fn fix_remotes(filter: &TagFilter) -> Result<(), Error> {
+    for spec in config::load_matching(filter)? {
+        let dest = spec.local_dir()?;
+        if !Path::new(dest).exists() {
+            continue;
+        }
+
+        let repo = match git2::Repository::open(dest) {
+            Ok(repo) => repo,
+            Err(ref e) if e.code() == git2::ErrorCode::NotFound => continue,
+            Err(e) => Err(e)?,
+        };
+
+        let url = spec.url.as_str();
+        match git::remote_url(&repo, spec.remote())? {
+            Some(ref origin) if spec.url.same_repo(origin) => (),
+            Some(origin) => {
+                repo.remote_set_url(spec.remote(), url)?;
+                println!("{}: {} -> {}", dest, origin, url);
+            }
+            None => {
+                repo.remote(spec.remote(), url)?;
+                println!("{}: added {}", dest, url);
+            }
+        }
+    }
+
+    Ok(())
+}
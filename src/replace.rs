@@ -0,0 +1,166 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::bail;
+use anyhow::Error;
+use grep_matcher::Captures;
+use grep_matcher::Matcher;
+use grep_regex::RegexMatcher;
+use rayon::iter::IntoParallelIterator;
+use rayon::iter::ParallelIterator;
+
+use super::config;
+use super::git;
+use super::grep;
+use crate::tags::TagFilter;
+use config::Spec;
+
+enum Outcome {
+    Absent,
+    /// has uncommitted changes, which we'd be mixing our changes into
+    Dirty,
+    Rewrites(Vec<Rewrite>),
+}
+
+struct Rewrite {
+    /// unified, with paths relative to the workspace
+    diff: String,
+    replacements: usize,
+}
+
+/// Replace `pattern` in every file `grep --worktree` would search, showing a diff, and only
+/// changing anything if `write`.
+///
+/// `replacement` can refer to groups in the pattern, like `$1` or `${name}`.
+pub fn replace(
+    filter: &TagFilter,
+    pattern: &str,
+    replacement: &str,
+    globs: &[&String],
+    options: &grep::Options,
+    write: bool,
+) -> Result<(), Error> {
+    let globs = grep::build_globs(globs)?;
+    let matcher = options.matcher(pattern)?;
+
+    let results = config::load_matching(filter)?
+        .into_par_iter()
+        .map(|spec| {
+            let result = replace_in(&spec, &matcher, replacement, &globs, options, write);
+            (spec, result)
+        })
+        .collect::<Vec<(Spec, Result<Outcome, Error>)>>();
+
+    let mut files = 0;
+    let mut replacements = 0;
+    let mut repos = 0;
+    let mut failed = 0;
+    for (spec, result) in &results {
+        match result {
+            Ok(Outcome::Absent) => (),
+            Ok(Outcome::Dirty) => println!(
+                "{}: has uncommitted changes, not touching it",
+                spec.local_dir()?
+            ),
+            Ok(Outcome::Rewrites(rewrites)) => {
+                if !rewrites.is_empty() {
+                    repos += 1;
+                }
+                for rewrite in rewrites {
+                    if !write {
+                        print!("{}", rewrite.diff);
+                    }
+                    files += 1;
+                    replacements += rewrite.replacements;
+                }
+            }
+            Err(e) => {
+                failed += 1;
+                println!("{}: failed: {:#}", spec.url.as_str(), e);
+            }
+        }
+    }
+
+    println!(
+        "{} {} replacements in {} files in {} repos{}",
+        if write { "made" } else { "would make" },
+        replacements,
+        files,
+        repos,
+        if write { "" } else { ", use --write to apply" }
+    );
+
+    if failed > 0 {
+        bail!("{} of {} repos failed", failed, results.len());
+    }
+
+    Ok(())
+}
+
+fn replace_in(
+    spec: &Spec,
+    matcher: &RegexMatcher,
+    replacement: &str,
+    globs: &globset::GlobSet,
+    options: &grep::Options,
+    write: bool,
+) -> Result<Outcome, Error> {
+    let dest = spec.local_dir()?;
+    if !Path::new(dest).exists() {
+        return Ok(Outcome::Absent);
+    }
+    let repo = git2::Repository::open(dest)?;
+    if !git::statuses(&repo)?.is_empty() {
+        return Ok(Outcome::Dirty);
+    }
+
+    let mut rewrites = Vec::new();
+    for (path, on_disk) in grep::worktree_paths(dest, &repo, globs, options.text)? {
+        if options
+            .max_filesize
+            .is_some_and(|max| fs::metadata(&on_disk).map_or(true, |m| m.len() > max))
+        {
+            continue;
+        }
+        let old = fs::read(&on_disk)?;
+        // binary, as far as `grep` is concerned
+        if !options.text && old.contains(&0) {
+            continue;
+        }
+
+        let mut replacements = 0;
+        let mut new = Vec::with_capacity(old.len());
+        let mut caps = matcher.new_captures()?;
+        matcher.replace_with_captures(&old, &mut caps, &mut new, |caps, new| {
+            caps.interpolate(
+                |name| matcher.capture_index(name),
+                &old,
+                replacement.as_bytes(),
+                new,
+            );
+            replacements += 1;
+            true
+        })?;
+        if old == new {
+            continue;
+        }
+
+        let shown = Path::new(dest).join(&path);
+        let diff = git2::Patch::from_buffers(&old, Some(&shown), &new, Some(&shown), None)?
+            .to_buf()?
+            .as_str()
+            .unwrap_or("(non-utf-8 diff)\n")
+            .to_string();
+
+        if write {
+            let mut temp = tempfile_fast::Sponge::new_for(&on_disk)?;
+            temp.write_all(&new)?;
+            temp.commit()?;
+        }
+
+        rewrites.push(Rewrite { diff, replacements });
+    }
+
+    Ok(Outcome::Rewrites(rewrites))
+}
@@ -0,0 +1,158 @@
+use std::collections::HashSet;
+use std::fs;
+use std::str::FromStr;
+
+use anyhow::Error;
+
+use super::config;
+use super::git;
+use crate::git_url::GitUrl;
+use config::Spec;
+
+/// A git repo next to `.gitgeoff` which it doesn't mention.
+struct Stray {
+    dir: String,
+    origin: Option<GitUrl>,
+}
+
+/// List the strays, and optionally `add` them to `.gitgeoff`.
+pub fn scan(add: bool) -> Result<(), Error> {
+    let specs = config::load()?;
+    let known = specs
+        .iter()
+        .map(|spec| spec.local_dir())
+        .collect::<Result<HashSet<&str>, Error>>()?;
+
+    let mut strays = Vec::new();
+    for entry in fs::read_dir(".")? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() || !entry.path().join(".git").exists() {
+            continue;
+        }
+        let dir = entry.file_name().to_string_lossy().to_string();
+        if known.contains(dir.as_str()) {
+            continue;
+        }
+        let repo = match git2::Repository::open(entry.path()) {
+            Ok(repo) => repo,
+            Err(e) => {
+                println!("{}: can't open: {}", dir, e.message());
+                continue;
+            }
+        };
+        let origin = git::remote_url(&repo, "origin")?.and_then(|url| GitUrl::from_str(&url).ok());
+        strays.push(Stray { dir, origin });
+    }
+    strays.sort_by(|a, b| a.dir.cmp(&b.dir));
+
+    let mut addable = 0;
+    for stray in &strays {
+        let origin = match &stray.origin {
+            Some(origin) => origin,
+            None => {
+                println!("{}: no origin", stray.dir);
+                continue;
+            }
+        };
+        if let Some(reason) = unaddable(&specs, &stray.dir, origin) {
+            println!("{}: {} {}, not adding", stray.dir, origin.as_str(), reason);
+            continue;
+        }
+        addable += 1;
+        let tags = infer_tags(&specs, origin);
+        let mut tags = tags.iter().map(|tag| tag.as_str()).collect::<Vec<_>>();
+        tags.sort_unstable();
+        println!("{}: {} {}", stray.dir, origin.as_str(), tags.join(" "));
+        if add {
+            config::append(origin, &tags)?;
+        }
+    }
+
+    if !add && addable > 0 {
+        println!("(use `scan --add` to add these to .gitgeoff)");
+    }
+
+    Ok(())
+}
+
+/// Why a checkout in `dir` of `origin` can't be listed alongside `specs`, if it can't.
+fn unaddable(specs: &[Spec], dir: &str, origin: &GitUrl) -> Option<String> {
+    let normalized = origin.normalized();
+    if let Some(listed) = specs
+        .iter()
+        .find(|spec| spec.url.normalized() == normalized)
+    {
+        return Some(format!(
+            "is already listed at {}, checked out to {}",
+            listed.defined,
+            listed.local_dir().unwrap_or("nowhere")
+        ));
+    }
+    // a spec for this url would point somewhere else, maybe at another spec's checkout
+    let expected = origin.local_dir().ok();
+    if expected != Some(dir) {
+        return Some(format!(
+            "would be cloned to {}",
+            expected.unwrap_or("nowhere")
+        ));
+    }
+    None
+}
+
+/// The tags every known repo from the same place has, e.g. the same github org.
+fn infer_tags(specs: &[Spec], url: &GitUrl) -> HashSet<String> {
+    let namespace = url.namespace();
+    let mut neighbours = specs
+        .iter()
+        .filter(|spec| spec.url.namespace() == namespace);
+    let first = match neighbours.next() {
+        Some(first) => first.tags.clone(),
+        None => return HashSet::new(),
+    };
+    neighbours.fold(first, |common, spec| {
+        common.intersection(&spec.tags).cloned().collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::str::FromStr;
+
+    use anyhow::Error;
+
+    use super::unaddable;
+    use crate::config::Location;
+    use crate::config::Spec;
+    use crate::git_url::GitUrl;
+
+    #[test]
+    fn addable() -> Result<(), Error> {
+        let defined = Location {
+            file: ".gitgeoff".to_string(),
+            line: 1,
+        };
+        let mut beta = Spec::new(
+            GitUrl::from_str("https://example.com/up/beta")?,
+            HashSet::new(),
+            defined,
+        );
+        beta.path = Some("b2".to_string());
+        let specs = [beta];
+
+        let url = |url: &str| GitUrl::from_str(url);
+        assert_eq!(
+            Some("is already listed at .gitgeoff:1, checked out to b2".to_string()),
+            unaddable(&specs, "beta", &url("https://example.com/up/beta.git")?)
+        );
+        assert_eq!(
+            Some("would be cloned to gamma".to_string()),
+            unaddable(&specs, "renamed", &url("https://example.com/up/gamma")?)
+        );
+        assert_eq!(
+            None,
+            unaddable(&specs, "gamma", &url("https://example.com/up/gamma")?)
+        );
+        Ok(())
+    }
+}
@@ -1,97 +1,346 @@
+use std::io;
+use std::io::Write;
 use std::path::Path;
+use std::str::FromStr;
 
 use anyhow::anyhow;
+use anyhow::bail;
 use anyhow::Context;
 use anyhow::Error;
 use rayon::iter::IntoParallelIterator;
 use rayon::iter::ParallelIterator;
 
 use super::config;
+use super::fetch;
 use super::git;
 use super::infect;
+use super::progress;
+use super::Cache;
+use crate::tags::TagFilter;
 use config::Spec;
 
-#[derive(PartialEq, Eq, Clone, Debug)]
+#[derive(PartialEq, Eq, Clone, Debug, serde_derive::Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
 pub enum Status {
     Absent,
-    Changes(Vec<String>, git::Variance),
+    /// the directory exists, but isn't a git repo
+    NotARepo,
+    /// the remote, usually `origin`, isn't the url from `.gitgeoff`, so everything else would be
+    /// misleading
+    WrongRemote {
+        origin: Option<String>,
+    },
+    Changes {
+        summary: git::ChangeSummary,
+        changes: Vec<git::Change>,
+        variance: git::Variance,
+    },
     Clean,
 }
 
-pub fn status(update: bool) -> Result<(), Error> {
-    let status: Vec<(Spec, Status)> = config::load()?
+/// Every local branch, and stashes, for `--branches`.
+#[derive(Clone, Debug, serde_derive::Serialize)]
+pub struct Branches {
+    branches: Vec<git::BranchState>,
+    stashes: Vec<String>,
+}
+
+struct Report {
+    status: Status,
+    branches: Option<Branches>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Format {
+    Text,
+    Json,
+    Ndjson,
+}
+
+pub struct Options {
+    pub update: bool,
+    pub retries: u32,
+    pub only_failed: bool,
+    pub format: Format,
+    pub verbose: bool,
+    pub branches: bool,
+}
+
+/// One repo in `--format json`.
+#[derive(serde_derive::Serialize)]
+struct Record<'s> {
+    url: &'s str,
+    local_dir: Option<&'s str>,
+    tags: Vec<&'s str>,
+    #[serde(flatten)]
+    outcome: Outcome<'s>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    branches: Option<&'s Branches>,
+}
+
+#[derive(serde_derive::Serialize)]
+#[serde(untagged)]
+enum Outcome<'s> {
+    Status(&'s Status),
+    Error { status: &'static str, error: String },
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Format, Error> {
+        Ok(match s {
+            "text" => Format::Text,
+            "json" => Format::Json,
+            "ndjson" => Format::Ndjson,
+            other => bail!("unknown format: {:?}", other),
+        })
+    }
+}
+
+pub fn status(cache: &Cache, filter: &TagFilter, options: &Options) -> Result<(), Error> {
+    let Options {
+        update,
+        retries,
+        only_failed,
+        format,
+        verbose,
+        branches,
+    } = *options;
+    let mut specs = config::load_matching(filter)?;
+    if only_failed {
+        let failed = fetch::last_failures(cache)?;
+        specs.retain(|spec| failed.contains(spec.url.as_str()));
+    }
+    let update = update || only_failed;
+
+    let display = progress::Display::new(specs.len());
+    let results: Vec<(Spec, Result<Report, Error>)> = specs
         .into_par_iter()
-        .map(|spec| -> Result<_, Error> {
-            let dest = spec.url.local_dir()?;
-            let dest = Path::new(dest);
-            if !dest.exists() {
-                return Ok((spec, Status::Absent));
-            }
-            let repo = git2::Repository::open(dest)?;
-            let configured = infect::fetches_remote_head(&repo)?;
-            if update || !configured {
-                if !configured {
-                    repo.remote_add_fetch("origin", "+HEAD:refs/remotes/origin/REMOTE_HEAD")?;
-                }
-                git::fetch_origin_default(&repo)
-                    .with_context(|| anyhow!("fetching {:?} -> {:?}", spec.url, dest))?;
-            }
-            let status =
-                find_variance(&repo).with_context(|| anyhow!("finding status of {:?}", dest))?;
-            Ok((spec, status))
+        .map(|spec| {
+            let report = find_status(&spec, update, retries, &display).and_then(|status| {
+                Ok(Report {
+                    branches: match (&status, branches) {
+                        (Status::Absent | Status::NotARepo, _) | (_, false) => None,
+                        _ => Some(find_branches(&spec)?),
+                    },
+                    status,
+                })
+            });
+            (spec, report)
         })
-        .collect::<Result<_, _>>()?;
+        .collect();
+    display.finish();
 
-    println!(
-        "absent: {}",
-        status
-            .iter()
-            .filter_map(|(spec, status)| match status {
-                Status::Absent => spec.url.local_dir().ok(),
-                _ => None,
-            })
-            .collect::<Vec<&str>>()
-            .join(", ")
-    );
+    if update {
+        fetch::record_failures(
+            cache,
+            results.iter().map(|(spec, _)| spec.url.as_str()),
+            results
+                .iter()
+                .filter(|(_, status)| status.is_err())
+                .map(|(spec, _)| spec.url.as_str()),
+        )?;
+    }
+
+    let failed = results.iter().filter(|(_, result)| result.is_err()).count();
+    match format {
+        Format::Text => print_text(&results, verbose)?,
+        Format::Json | Format::Ndjson => print_json(&results, format)?,
+    }
+
+    if failed > 0 {
+        bail!("{} of {} repos failed", failed, results.len());
+    }
 
-    println!(
-        "clean: {}",
-        status
+    Ok(())
+}
+
+fn print_text(results: &[(Spec, Result<Report, Error>)], verbose: bool) -> Result<(), Error> {
+    let with_status = |wanted: &Status| {
+        results
             .iter()
-            .filter_map(|(spec, status)| match status {
-                Status::Clean => spec.url.local_dir().ok(),
+            .filter_map(|(spec, report)| match report {
+                Ok(report) if report.status == *wanted => spec.local_dir().ok(),
                 _ => None,
             })
             .collect::<Vec<&str>>()
             .join(", ")
-    );
+    };
+
+    println!("absent: {}", with_status(&Status::Absent));
+    println!("clean: {}", with_status(&Status::Clean));
+
+    let not_repos = with_status(&Status::NotARepo);
+    if !not_repos.is_empty() {
+        println!("not a repo: {}", not_repos);
+    }
+
+    for (spec, report) in results {
+        if let Ok(Report {
+            status: Status::WrongRemote { origin },
+            ..
+        }) = report
+        {
+            println!(
+                "{}: {} is {}, not {} (see `fix-remotes`)",
+                spec.local_dir()?,
+                spec.remote(),
+                origin.as_deref().unwrap_or("missing"),
+                spec.url.as_str()
+            );
+        }
+    }
 
-    for (spec, stat) in status {
-        let (changes, variance) = match stat {
-            Status::Changes(changes, variance) => (changes, variance),
+    for (spec, report) in results {
+        let (summary, changes, variance) = match report {
+            Ok(Report {
+                status:
+                    Status::Changes {
+                        summary,
+                        changes,
+                        variance,
+                    },
+                ..
+            }) => (summary, changes, variance),
             _ => continue,
         };
-        let suffix = if changes.len() > 2 { ", ..." } else { "" };
-        println!(
-            "{}: ({:?}) {}{}",
-            spec.url.local_dir()?,
-            variance,
-            changes.into_iter().take(2).collect::<Vec<_>>().join(", "),
-            suffix
-        );
+        println!("{}: ({:?}) {}", spec.local_dir()?, variance, summary);
+        if verbose {
+            for change in changes {
+                println!("  {}", change.short());
+            }
+        }
+    }
+
+    for (spec, report) in results {
+        let branches = match report {
+            Ok(Report {
+                branches: Some(branches),
+                ..
+            }) => branches,
+            _ => continue,
+        };
+        let mut notes = branches
+            .branches
+            .iter()
+            .filter(|branch| branch.is_notable())
+            .map(|branch| branch.to_string())
+            .collect::<Vec<_>>();
+        match branches.stashes.len() {
+            0 => (),
+            1 => notes.push("1 stash".to_string()),
+            n => notes.push(format!("{} stashes", n)),
+        }
+        if !notes.is_empty() {
+            println!("{} branches: {}", spec.local_dir()?, notes.join(", "));
+        }
+    }
+
+    let errors = results
+        .iter()
+        .filter_map(|(spec, result)| result.as_ref().err().map(|e| (spec, e)))
+        .collect::<Vec<_>>();
+    if !errors.is_empty() {
+        println!("errors:");
+        for (spec, e) in errors {
+            println!("{}: {:#}", spec.local_dir().unwrap_or(spec.url.as_str()), e);
+        }
+    }
+
+    Ok(())
+}
+
+fn print_json(results: &[(Spec, Result<Report, Error>)], format: Format) -> Result<(), Error> {
+    let records = results.iter().map(|(spec, result)| {
+        let mut tags = spec.tags.iter().map(|t| t.as_str()).collect::<Vec<_>>();
+        tags.sort_unstable();
+        Record {
+            url: spec.url.as_str(),
+            local_dir: spec.local_dir().ok(),
+            tags,
+            outcome: match result {
+                Ok(report) => Outcome::Status(&report.status),
+                Err(e) => Outcome::Error {
+                    status: "error",
+                    error: format!("{:#}", e),
+                },
+            },
+            branches: result.as_ref().ok().and_then(|r| r.branches.as_ref()),
+        }
+    });
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    if Format::Ndjson == format {
+        for record in records {
+            serde_json::to_writer(&mut stdout, &record)?;
+            writeln!(stdout)?;
+        }
+    } else {
+        serde_json::to_writer_pretty(&mut stdout, &records.collect::<Vec<_>>())?;
+        writeln!(stdout)?;
     }
 
     Ok(())
 }
 
-fn find_variance(repo: &git2::Repository) -> Result<Status, Error> {
-    let variance = git::variance_from_origin_head(&repo)?;
-    let some_statuses = git::first_statuses(&repo)?;
-    Ok(
-        if !some_statuses.is_empty() || variance != git::Variance::Equal {
-            Status::Changes(some_statuses, variance)
-        } else {
-            Status::Clean
-        },
-    )
+fn find_status(
+    spec: &Spec,
+    update: bool,
+    retries: u32,
+    display: &progress::Display,
+) -> Result<Status, Error> {
+    let dest = spec.local_dir()?;
+    let dest = Path::new(dest);
+    if !dest.exists() {
+        display.skip();
+        return Ok(Status::Absent);
+    }
+    let repo = match git2::Repository::open(dest) {
+        Ok(repo) => repo,
+        Err(ref e) if e.code() == git2::ErrorCode::NotFound => {
+            display.skip();
+            return Ok(Status::NotARepo);
+        }
+        Err(e) => Err(e)?,
+    };
+    let origin = git::remote_url(&repo, spec.remote())?;
+    if !origin.as_deref().is_some_and(|url| spec.url.same_repo(url)) {
+        display.skip();
+        return Ok(Status::WrongRemote { origin });
+    }
+    if update || !infect::fetches_remote_head(&repo, spec.remote())? {
+        let progress = display.start(spec.local_dir()?);
+        fetch::with_retries(retries, spec.url.as_str(), || {
+            fetch::update(spec, &repo, &progress)
+        })?;
+    } else {
+        display.skip();
+    }
+    find_variance(&repo, &spec.upstream()).with_context(|| anyhow!("finding status of {:?}", dest))
+}
+
+fn find_branches(spec: &Spec) -> Result<Branches, Error> {
+    let dest = spec.local_dir()?;
+    let mut repo = git2::Repository::open(dest)?;
+    Ok(Branches {
+        branches: git::branch_states(&repo, &spec.upstream())
+            .with_context(|| anyhow!("finding branches of {:?}", dest))?,
+        stashes: git::stashes(&mut repo)?,
+    })
+}
+
+fn find_variance(repo: &git2::Repository, upstream: &str) -> Result<Status, Error> {
+    let variance = git::variance_from_upstream(repo, upstream)?;
+    let changes = git::statuses(repo)?;
+    Ok(if !changes.is_empty() || variance != git::Variance::Equal {
+        Status::Changes {
+            summary: git::ChangeSummary::of(&changes),
+            changes,
+            variance,
+        }
+    } else {
+        Status::Clean
+    })
 }
@@ -0,0 +1,155 @@
+use std::collections::HashSet;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Error;
+
+/// Matches repos which have no tags at all, e.g. `-t @untagged`.
+pub const UNTAGGED: &str = "@untagged";
+
+/// A selection over the tags in `.gitgeoff`, from `--tags`.
+///
+/// Each `--tags` expression is a list of alternatives separated by `|`, each of which is
+/// a list of terms separated by `,`, all of which must match. Repeating `--tags` adds more
+/// alternatives. A term is a tag name, `!tag` to exclude it, or `@untagged`.
+///
+/// For example, `backend,!archived|frontend` selects everything tagged `frontend`, and
+/// anything tagged `backend` which is not also tagged `archived`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TagFilter {
+    alternatives: Vec<Vec<Term>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Term {
+    Has(String),
+    Lacks(String),
+    Untagged,
+}
+
+impl TagFilter {
+    pub fn parse<S: AsRef<str>>(expressions: &[S]) -> Result<TagFilter, Error> {
+        let mut alternatives = Vec::with_capacity(expressions.len());
+        for expression in expressions {
+            let expression = expression.as_ref();
+            for alternative in expression.split('|') {
+                let terms = alternative
+                    .split(',')
+                    .map(|term| term.trim())
+                    .filter(|term| !term.is_empty())
+                    .map(Term::parse)
+                    .collect::<Result<Vec<Term>, Error>>()
+                    .map_err(|e| anyhow!("parsing tags {:?}: {}", expression, e))?;
+                if terms.is_empty() {
+                    bail!("empty alternative in tags {:?}", expression);
+                }
+                alternatives.push(terms);
+            }
+        }
+        Ok(TagFilter { alternatives })
+    }
+
+    /// An empty filter (no `--tags`) matches everything.
+    pub fn is_empty(&self) -> bool {
+        self.alternatives.is_empty()
+    }
+
+    pub fn matches(&self, tags: &HashSet<String>) -> bool {
+        self.is_empty()
+            || self
+                .alternatives
+                .iter()
+                .any(|terms| terms.iter().all(|term| term.matches(tags)))
+    }
+}
+
+/// Whether a tag from `.gitgeoff` can be selected by a `TagFilter`.
+pub fn is_valid(tag: &str) -> bool {
+    !tag.is_empty() && !tag.contains(|c: char| c.is_whitespace() || "!@|,".contains(c))
+}
+
+impl Term {
+    fn parse(term: &str) -> Result<Term, Error> {
+        if term == UNTAGGED {
+            return Ok(Term::Untagged);
+        }
+
+        let (negated, tag) = match term.strip_prefix('!') {
+            Some(tag) => (true, tag.trim()),
+            None => (false, term),
+        };
+
+        if !is_valid(tag) {
+            bail!("invalid tag {:?}", term);
+        }
+
+        Ok(if negated {
+            Term::Lacks(tag.to_string())
+        } else {
+            Term::Has(tag.to_string())
+        })
+    }
+
+    fn matches(&self, tags: &HashSet<String>) -> bool {
+        match self {
+            Term::Has(tag) => tags.contains(tag),
+            Term::Lacks(tag) => !tags.contains(tag),
+            Term::Untagged => tags.is_empty(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use anyhow::Error;
+
+    use super::TagFilter;
+
+    fn tags(list: &[&str]) -> HashSet<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn and_not() -> Result<(), Error> {
+        let filter = TagFilter::parse(&["backend,!archived"])?;
+        assert!(filter.matches(&tags(&["backend"])));
+        assert!(filter.matches(&tags(&["backend", "rust"])));
+        assert!(!filter.matches(&tags(&["backend", "archived"])));
+        assert!(!filter.matches(&tags(&["frontend"])));
+        assert!(!filter.matches(&tags(&[])));
+        Ok(())
+    }
+
+    #[test]
+    fn or() -> Result<(), Error> {
+        let piped = TagFilter::parse(&["backend,!archived|frontend"])?;
+        let repeated = TagFilter::parse(&["backend,!archived", "frontend"])?;
+        assert_eq!(piped, repeated);
+        assert!(piped.matches(&tags(&["frontend", "archived"])));
+        assert!(piped.matches(&tags(&["backend"])));
+        assert!(!piped.matches(&tags(&["backend", "archived"])));
+        Ok(())
+    }
+
+    #[test]
+    fn untagged() -> Result<(), Error> {
+        let filter = TagFilter::parse(&["@untagged|legacy"])?;
+        assert!(filter.matches(&tags(&[])));
+        assert!(filter.matches(&tags(&["legacy"])));
+        assert!(!filter.matches(&tags(&["backend"])));
+        Ok(())
+    }
+
+    #[test]
+    fn empty() -> Result<(), Error> {
+        let filter = TagFilter::parse::<&str>(&[])?;
+        assert!(filter.matches(&tags(&[])));
+        assert!(filter.matches(&tags(&["backend"])));
+        assert!(TagFilter::parse(&["backend||frontend"]).is_err());
+        assert!(TagFilter::parse(&["!"]).is_err());
+        assert!(TagFilter::parse(&["@other"]).is_err());
+        Ok(())
+    }
+}